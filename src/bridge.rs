@@ -0,0 +1,116 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use fd::FileDesc;
+use proxy::{Proxy, new_pipe};
+use std::io;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::JoinHandle;
+
+/// Forward data in both directions between two file descriptors (e.g. two TTY masters)
+pub struct Bridge {
+    // Need to keep the file descriptors open until the threads are joined
+    #[allow(dead_code)]
+    a: FileDesc,
+    #[allow(dead_code)]
+    b: FileDesc,
+    proxy: Proxy,
+    flush_event: Receiver<()>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Bridge `a` and `b` until one of them is closed
+///
+/// Unlike a `TtyClient`, the configuration of `a` and `b` is not changed: neither of them is
+/// expected to be a user terminal. Their append flag must not be set.
+pub fn bridge<T, U>(a: T, b: U) -> io::Result<Bridge>
+        where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
+    let a = FileDesc::new(a.into_raw_fd(), true);
+    let b = FileDesc::new(b.into_raw_fd(), true);
+
+    // Create all the pipes first to not leave a partial bridge
+    let a2b = try!(new_pipe(None));
+    let b2a = try!(new_pipe(None));
+
+    let (event_tx, event_rx) = channel();
+    let proxy = try!(Proxy::new(event_tx));
+    let mut threads = Vec::new();
+    let directions = vec![(a2b, a.as_raw_fd(), b.as_raw_fd()), (b2a, b.as_raw_fd(), a.as_raw_fd())];
+    for (pipe, fd_in, fd_out) in directions {
+        let (tx, rx) = (pipe.writer, pipe.reader);
+        threads.push(proxy.spawn_splice_loop(fd_in, tx.as_raw_fd(), tx, None, None));
+        threads.push(proxy.spawn_splice_loop(rx.as_raw_fd(), fd_out, rx, None, None));
+    }
+    Ok(Bridge {
+        a,
+        b,
+        proxy,
+        flush_event: event_rx,
+        threads,
+    })
+}
+
+impl Bridge {
+    /// Wait until the bridge broke (e.g. one side is closed)
+    pub fn wait(&self) {
+        while !self.proxy.do_flush.load(Relaxed) {
+            let _ = self.flush_event.recv();
+        }
+    }
+
+    /// Get the error which stopped the bridge, if any
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.proxy.take_error()
+    }
+}
+
+impl Drop for Bridge {
+    /// Flush the pending data and close both file descriptors
+    fn drop(&mut self) {
+        self.proxy.request_flush();
+        // The threads must not use the file descriptors once closed
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use TtyServer;
+
+    #[test]
+    fn forward_both_ways() {
+        // Raw TTYs don't echo the forwarded input back to the bridge
+        let mut a = TtyServer::new_raw(None).unwrap();
+        let b = TtyServer::new_raw(None).unwrap();
+        let bridge = bridge(a.get_master().try_clone().unwrap(),
+                            b.get_master().try_clone().unwrap()).unwrap();
+        let mut buf = [0; 2];
+        a.slave().unwrap().write_all(b"hi").unwrap();
+        b.slave().unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        b.slave().unwrap().write_all(b"ho").unwrap();
+        a.slave().unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ho");
+        // The master hangs up once its slave is closed
+        drop(a.take_slave());
+        bridge.wait();
+        assert!(bridge.take_error().is_none());
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use ffi::WinSize;
+use scan::{find, partial_len};
+use std::mem;
+use std::str;
+
+// Control messages are APC strings, ignored by the terminals which don't know them:
+// ESC _ tty-rs ; <name> [; <argument>]* ESC \
+const CONTROL_START: &[u8] = b"\x1b_tty-rs;";
+const CONTROL_END: &[u8] = b"\x1b\\";
+
+// Longest message body kept while waiting for its end
+const CONTROL_MAX_LEN: usize = 64;
+
+/// Message sent in-band by a peer without out-of-band signaling (e.g. a socket)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlMsg {
+    /// Set the terminal window size
+    Resize(WinSize),
+}
+
+impl ControlMsg {
+    /// Serialize as an escape sequence recognized by `ControlParser`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = match *self {
+            ControlMsg::Resize(ws) => format!("resize;{};{}", ws.cols(), ws.rows()),
+        };
+        let mut msg = CONTROL_START.to_vec();
+        msg.extend_from_slice(body.as_bytes());
+        msg.extend_from_slice(CONTROL_END);
+        msg
+    }
+
+    fn parse(body: &[u8]) -> Option<ControlMsg> {
+        let body = match str::from_utf8(body) {
+            Ok(b) => b,
+            Err(_) => return None,
+        };
+        let mut args = body.split(';');
+        match (args.next(), args.next(), args.next(), args.next()) {
+            (Some("resize"), Some(cols), Some(rows), None) => match (cols.parse(), rows.parse()) {
+                (Ok(cols), Ok(rows)) => Some(ControlMsg::Resize(WinSize::from((cols, rows)))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Extract the control messages (cf. `ControlMsg::to_bytes()`) from a peer stream
+///
+/// A message may be split across several calls to `feed()`. The invalid or too long messages are
+/// left in the data.
+///
+/// The proxy forwards the peer input as is, hence the stream must be filtered before reaching it,
+/// e.g. reading a socket and writing the data to a pipe bound as the peer input (cf.
+/// `TtyClient::new_split()`), while applying the messages with `TtyClient::handle_control()`.
+pub struct ControlParser {
+    // Possible beginning of a message
+    pending: Vec<u8>,
+}
+
+impl ControlParser {
+    pub fn new() -> ControlParser {
+        ControlParser {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Return the data to forward and the messages found in `data`
+    pub fn feed(&mut self, data: &[u8]) -> (Vec<u8>, Vec<ControlMsg>) {
+        let mut buf = mem::take(&mut self.pending);
+        buf.extend_from_slice(data);
+        let mut out = Vec::with_capacity(buf.len());
+        let mut msgs = Vec::new();
+        let mut pos = 0;
+        loop {
+            let start = match find(&buf[pos..], CONTROL_START) {
+                Some(i) => pos + i,
+                None => {
+                    // Hold back a message start split across reads
+                    let end = buf.len() - partial_len(&buf[pos..], CONTROL_START);
+                    out.extend_from_slice(&buf[pos..end]);
+                    self.pending = buf[end..].to_vec();
+                    return (out, msgs);
+                },
+            };
+            out.extend_from_slice(&buf[pos..start]);
+            let body = start + CONTROL_START.len();
+            match find(&buf[body..], CONTROL_END) {
+                Some(len) if len <= CONTROL_MAX_LEN => {
+                    let end = body + len + CONTROL_END.len();
+                    match ControlMsg::parse(&buf[body..body + len]) {
+                        Some(msg) => msgs.push(msg),
+                        None => out.extend_from_slice(&buf[start..end]),
+                    }
+                    pos = end;
+                },
+                None if buf.len() - body < CONTROL_MAX_LEN + CONTROL_END.len() => {
+                    self.pending = buf[start..].to_vec();
+                    return (out, msgs);
+                },
+                // Not a message
+                _ => {
+                    out.extend_from_slice(&buf[start..body]);
+                    pos = body;
+                },
+            }
+        }
+    }
+
+    /// Return the data held back, if any, e.g. once the peer stream ended
+    pub fn finish(&mut self) -> Vec<u8> {
+        mem::take(&mut self.pending)
+    }
+}
+
+impl Default for ControlParser {
+    fn default() -> ControlParser {
+        ControlParser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resize(cols: u16, rows: u16) -> ControlMsg {
+        ControlMsg::Resize(WinSize::from((cols, rows)))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data = b"ab".to_vec();
+        data.extend_from_slice(&resize(100, 30).to_bytes());
+        data.extend_from_slice(b"cd");
+        let mut parser = ControlParser::new();
+        assert_eq!(parser.feed(&data), (b"abcd".to_vec(), vec![resize(100, 30)]));
+        assert!(parser.finish().is_empty());
+    }
+
+    #[test]
+    fn split_message() {
+        let msg = resize(132, 43).to_bytes();
+        let mut parser = ControlParser::new();
+        for i in 1..msg.len() {
+            let (head, tail) = msg.split_at(i);
+            assert_eq!(parser.feed(head), (Vec::new(), Vec::new()));
+            assert_eq!(parser.feed(tail), (Vec::new(), vec![resize(132, 43)]));
+        }
+    }
+
+    #[test]
+    fn invalid_message() {
+        let data = b"\x1b_tty-rs;resize;x;24\x1b\\";
+        let mut parser = ControlParser::new();
+        assert_eq!(parser.feed(data), (data.to_vec(), Vec::new()));
+    }
+
+    #[test]
+    fn unfinished_message() {
+        let mut parser = ControlParser::new();
+        assert_eq!(parser.feed(b"a\x1b_tty"), (b"a".to_vec(), Vec::new()));
+        assert_eq!(parser.finish(), b"\x1b_tty");
+        // Too long to be a message
+        let mut data = b"\x1b_tty-rs;".to_vec();
+        data.extend_from_slice(&[b'x'; CONTROL_MAX_LEN + CONTROL_END.len()]);
+        assert_eq!(parser.feed(&data), (data.clone(), Vec::new()));
+    }
+}
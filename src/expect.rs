@@ -0,0 +1,200 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc::{self, c_int};
+use proxy::poll_one;
+use regex::bytes::Regex;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::time::{Duration, Instant};
+
+// Default maximum of the output kept to match patterns
+const DEFAULT_MAX_BUFFER: usize = 64 * 1024;
+
+/// Wait for some patterns in the output of a TTY master
+pub struct Expect {
+    master: File,
+    buffer: Vec<u8>,
+    max_buffer: usize,
+}
+
+/// Groups of a matched pattern, the first one being the whole match
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Captures {
+    groups: Vec<Option<String>>,
+}
+
+impl Captures {
+    /// Get the group `i`, if it participated in the match
+    ///
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD`.
+    pub fn get(&self, i: usize) -> Option<&str> {
+        match self.groups.get(i) {
+            Some(Some(group)) => Some(group),
+            _ => None,
+        }
+    }
+
+    /// Number of groups, including the whole match
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Check if there is no group, which never happens for a match
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl Expect {
+    /// Read the output from `master` (e.g. a clone of `TtyServer::get_master()`)
+    ///
+    /// No `TtyClient` should forward the same master, otherwise the output would be split
+    /// between them.
+    pub fn new<T>(master: T) -> Expect where T: IntoRawFd {
+        Expect {
+            master: unsafe { File::from_raw_fd(master.into_raw_fd()) },
+            buffer: Vec::new(),
+            max_buffer: DEFAULT_MAX_BUFFER,
+        }
+    }
+
+    /// Only keep the last `max_buffer` bytes of unmatched output (64 KiB by default)
+    ///
+    /// A pattern spanning more output than this limit can't match.
+    pub fn max_buffer(mut self, max_buffer: usize) -> Expect {
+        self.max_buffer = max_buffer;
+        self
+    }
+
+    /// Wait until the output matches `re`, and consume the output up to the end of the match
+    ///
+    /// The pattern matches the raw output, which may not be valid UTF-8, to consume the exact
+    /// number of bytes. A `TimedOut` error is returned if nothing matched before `timeout`, and
+    /// an `UnexpectedEof` error if all the slave file descriptors are closed.
+    pub fn expect_regex(&mut self, re: &Regex, timeout: Duration) -> io::Result<Captures> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 4096];
+        loop {
+            let found = match re.captures(&self.buffer) {
+                Some(caps) => {
+                    let end = caps.get(0).map(|m| m.end()).unwrap_or(0);
+                    let groups = caps.iter()
+                        .map(|m| m.map(|m| String::from_utf8_lossy(m.as_bytes()).into_owned()))
+                        .collect();
+                    Some((end, groups))
+                },
+                None => None,
+            };
+            if let Some((end, groups)) = found {
+                self.buffer.drain(..end);
+                return Ok(Captures { groups });
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Pattern not found"));
+            }
+            let remaining = deadline - now;
+            // Round up to not spin during the last millisecond
+            let ms = remaining.as_secs() * 1000 + (remaining.subsec_nanos() as u64 + 999_999) / 1_000_000;
+            if !poll_one(self.master.as_raw_fd(), libc::POLLIN, cmp::min(ms, c_int::MAX as u64) as c_int) {
+                continue;
+            }
+            match self.master.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "TTY closed")),
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&buf[..n]);
+                    let excess = self.buffer.len().saturating_sub(self.max_buffer);
+                    self.buffer.drain(..excess);
+                },
+                // The master may be nonblocking
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted ||
+                    e.kind() == io::ErrorKind::WouldBlock => {},
+                // The master returns EIO once all the slave file descriptors are closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) =>
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "TTY closed")),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+    use TtyServer;
+
+    fn spawn(script: &str) -> (TtyServer, Expect, Child) {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        let child = server.spawn(cmd).unwrap();
+        let expect = Expect::new(server.get_master().try_clone().unwrap());
+        (server, expect, child)
+    }
+
+    #[test]
+    fn captures() {
+        let (_server, mut expect, mut child) = spawn("echo a=1 b=2");
+        let re = Regex::new(r"a=(\d) (c=\d)?b=(\d)").unwrap();
+        let caps = expect.expect_regex(&re, Duration::from_secs(5)).unwrap();
+        assert_eq!(caps.len(), 4);
+        assert!(!caps.is_empty());
+        assert_eq!(caps.get(0), Some("a=1 b=2"));
+        assert_eq!(caps.get(1), Some("1"));
+        assert_eq!(caps.get(2), None);
+        assert_eq!(caps.get(3), Some("2"));
+        // The output is consumed up to the end of the match
+        let re = Regex::new(r"^\r\n").unwrap();
+        assert!(expect.expect_regex(&re, Duration::from_secs(5)).is_ok());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn timeout() {
+        let (_server, mut expect, mut child) = spawn("sleep 5");
+        let re = Regex::new("never").unwrap();
+        let err = expect.expect_regex(&re, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn closed() {
+        let (_server, mut expect, mut child) = spawn("echo done");
+        let re = Regex::new("never").unwrap();
+        let err = expect.expect_regex(&re, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn max_buffer() {
+        let (_server, expect, mut child) = spawn("printf 0123456789; sleep 5");
+        let mut expect = expect.max_buffer(4);
+        // Only the last bytes of a read are kept
+        let re = Regex::new("0123").unwrap();
+        let err = expect.expect_regex(&re, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let re = Regex::new("6789").unwrap();
+        assert!(expect.expect_regex(&re, Duration::from_secs(5)).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
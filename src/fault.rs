@@ -0,0 +1,93 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use fd;
+use libc::c_int;
+use std::io;
+use std::os::unix::io::RawFd;
+use termios::{self, Termios};
+#[cfg(feature = "fault-injection")]
+use std::sync::Mutex;
+
+/// System call which can be forced to fail (cf. `inject_fault()`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syscall {
+    /// `grantpt(3)` while opening a PTY
+    Grantpt,
+    /// `splice(2)` in the proxy threads
+    Splice,
+    /// `tcsetattr(3)` on any TTY
+    Tcsetattr,
+    /// `fcntl(2)` while unsetting the append flag of a client file descriptor
+    UnsetAppendFlag,
+}
+
+// Calls to fail with their errno
+#[cfg(feature = "fault-injection")]
+static FAULTS: Mutex<Vec<(Syscall, c_int)>> = Mutex::new(Vec::new());
+
+/// Make all the next `call` fail with the `errno` error, until `clear_fault()` is called
+///
+/// This is only meant to exercise the error paths in tests (with the `fault-injection` feature).
+/// The faults are global to the process, hence concurrent tests must not inject conflicting ones.
+#[cfg(feature = "fault-injection")]
+pub fn inject_fault(call: Syscall, errno: c_int) {
+    let mut faults = match FAULTS.lock() {
+        Ok(f) => f,
+        Err(e) => e.into_inner(),
+    };
+    faults.retain(|&(c, _)| c != call);
+    faults.push((call, errno));
+}
+
+/// Stop making `call` fail
+#[cfg(feature = "fault-injection")]
+pub fn clear_fault(call: Syscall) {
+    let mut faults = match FAULTS.lock() {
+        Ok(f) => f,
+        Err(e) => e.into_inner(),
+    };
+    faults.retain(|&(c, _)| c != call);
+}
+
+// Return the injected error for `call`, if any
+#[cfg(feature = "fault-injection")]
+pub fn check(call: Syscall) -> io::Result<()> {
+    let faults = match FAULTS.lock() {
+        Ok(f) => f,
+        Err(e) => e.into_inner(),
+    };
+    match faults.iter().find(|&&(c, _)| c == call) {
+        Some(&(_, errno)) => Err(io::Error::from_raw_os_error(errno)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+#[inline(always)]
+pub fn check(_call: Syscall) -> io::Result<()> {
+    Ok(())
+}
+
+// Same as `termios::tcsetattr()` but may fail on demand
+pub fn tcsetattr(fd: RawFd, action: c_int, config: &Termios) -> io::Result<()> {
+    try!(check(Syscall::Tcsetattr));
+    termios::tcsetattr(fd, action, config)
+}
+
+// Same as `fd::unset_append_flag()` but may fail on demand
+pub fn unset_append_flag(fd: RawFd) -> io::Result<Option<c_int>> {
+    try!(check(Syscall::UnsetAppendFlag));
+    fd::unset_append_flag(fd)
+}
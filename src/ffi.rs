@@ -12,30 +12,33 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use fault::{self, Syscall, tcsetattr};
 use libc::{self, c_int, c_uint, c_ushort};
+use std::error::Error;
 use std::ffi::CString;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
-use termios::{self, Termios, tcsetattr};
+use termios::{self, Termios};
 
-const DEV_PTMX_PATH: &'static str = "/dev/ptmx";
-const DEV_PTS_PATH: &'static str = "/dev/pts";
+const DEV_PTMX_PATH: &str = "/dev/ptmx";
+const DEV_PTS_PATH: &str = "/dev/pts";
+const DEV_PTS_PTMX_PATH: &str = "/dev/pts/ptmx";
 
 mod raw {
     use libc::{c_int, c_uint};
 
-    // From asm-generic/fcntl.h
-    pub const O_CLOEXEC: c_int = 0o2000000;
-
     // From asm-generic/ioctls.h
     pub const TIOCGWINSZ: c_int = 0x5413;
     pub const TIOCSWINSZ: c_int = 0x5414;
     pub const TIOCGPTN: c_uint = 0x80045430;
+    pub const TIOCGPTLCK: c_uint = 0x80045439;
+    pub const TIOCGPTPEER: c_uint = 0x5441;
 
-    extern {
+    extern "C" {
         pub fn grantpt(fd: c_int) -> c_int;
         pub fn ioctl(fd: c_int, req: c_int, ...) -> c_int;
         pub fn unlockpt(fd: c_int) -> c_int;
@@ -44,6 +47,7 @@ mod raw {
 
 // From termios.h
 #[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct WinSize {
     ws_row: c_ushort,
     ws_col: c_ushort,
@@ -51,6 +55,49 @@ pub struct WinSize {
     ws_ypixel: c_ushort,
 }
 
+impl WinSize {
+    /// Number of rows (i.e. lines)
+    pub fn rows(&self) -> u16 {
+        self.ws_row
+    }
+
+    /// Number of columns
+    pub fn cols(&self) -> u16 {
+        self.ws_col
+    }
+
+    /// Number of columns and rows, in this order (i.e. width and height)
+    pub fn cols_rows(&self) -> (u16, u16) {
+        (self.ws_col, self.ws_row)
+    }
+}
+
+impl From<(u16, u16)> for WinSize {
+    /// Create from a number of columns and rows, in this order (i.e. width and height)
+    ///
+    /// The size in pixels is unset.
+    fn from((cols, rows): (u16, u16)) -> WinSize {
+        WinSize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+impl Default for WinSize {
+    /// Same as a standard 80x24 terminal
+    fn default() -> WinSize {
+        WinSize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
 pub fn get_winsize<T>(slave: &T) -> io::Result<WinSize> where T: AsRawFd {
     let mut ws = WinSize {
         ws_row: 0,
@@ -78,22 +125,110 @@ pub struct Pty {
 }
 
 fn open_noctty<T>(path: &T) -> io::Result<File> where T: AsRef<Path> {
-    let flags = raw::O_CLOEXEC | libc::O_NOCTTY | libc::O_RDWR;
+    let flags = libc::O_CLOEXEC | libc::O_NOCTTY | libc::O_RDWR;
     // The CString unwrap always succeed on unix
     let cstr = CString::new(path.as_ref().as_os_str().as_bytes()).unwrap();
     match unsafe { libc::open(cstr.as_ptr(), flags, 0) } {
         -1 => Err(io::Error::last_os_error()),
-        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+        fd => ensure_cloexec(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+// Kernels without O_CLOEXEC support silently ignore it: set FD_CLOEXEC afterwards, with the race
+// against a concurrent fork that it implies
+fn ensure_cloexec(file: File) -> io::Result<File> {
+    let fd = file.as_raw_fd();
+    match unsafe { libc::fcntl(fd, libc::F_GETFD) } {
+        -1 => Err(io::Error::last_os_error()),
+        flags if flags & libc::FD_CLOEXEC != 0 => Ok(file),
+        flags => match unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(file),
+        },
+    }
+}
+
+/// Open the slave TTY at `path` without making it the controlling terminal
+pub fn open_slave<T>(path: &T) -> io::Result<File> where T: AsRef<Path> {
+    open_noctty(path)
+}
+
+// Open the slave from the master itself, without any path lookup which may race with another
+// devpts mount or be denied by the slave permissions
+fn open_peer(master: &File) -> io::Result<File> {
+    let flags = libc::O_CLOEXEC | libc::O_NOCTTY | libc::O_RDWR;
+    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTPEER as c_int, flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => ensure_cloexec(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Error wrapped in an `io::Error` when no more PTY can be allocated (cf. `is_exhausted()`)
+#[derive(Debug)]
+pub struct ExhaustedError {
+    cause: io::Error,
+}
+
+impl fmt::Display for ExhaustedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PTY allocation exhausted: {}", self.cause)
+    }
+}
+
+impl Error for ExhaustedError {
+    fn description(&self) -> &str {
+        "PTY allocation exhausted"
+    }
+}
+
+/// Check if an error comes from the PTY or file descriptor limits, which may be transient
+pub fn is_exhausted(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(e) => e.is::<ExhaustedError>(),
+        None => false,
+    }
+}
+
+fn map_exhausted(err: io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EMFILE) | Some(libc::ENFILE) =>
+            io::Error::new(io::ErrorKind::Other, ExhaustedError { cause: err }),
+        _ => err,
     }
 }
 
 // Need our own `getpt()` to be able to open with O_CLOEXEC
+//
+// Some minimal containers only provide the devpts multiplexer.
 #[cfg(target_os = "linux")]
 pub fn getpt() -> io::Result<File> {
-    open_noctty(&DEV_PTMX_PATH)
+    match getpt_at(&DEV_PTMX_PATH) {
+        Ok(f) => Ok(f),
+        Err(e1) => if is_exhausted(&e1) {
+            Err(e1)
+        } else {
+            match getpt_at(&DEV_PTS_PTMX_PATH) {
+                Ok(f) => Ok(f),
+                Err(e2) => if is_exhausted(&e2) {
+                    Err(e2)
+                } else {
+                    Err(io::Error::new(e2.kind(), format!("Failed to open {} ({}) and {} ({})",
+                                                          DEV_PTMX_PATH, e1, DEV_PTS_PTMX_PATH, e2)))
+                },
+            }
+        },
+    }
+}
+
+/// Open a new master from the `ptmx` multiplexer
+///
+/// Running out of PTY or file descriptors is reported with an `ExhaustedError`.
+pub fn getpt_at<T>(ptmx: &T) -> io::Result<File> where T: AsRef<Path> {
+    open_noctty(ptmx).map_err(map_exhausted)
 }
 
 pub fn grantpt<T>(master: &mut T) -> io::Result<()> where T: AsRawFd {
+    try!(fault::check(Syscall::Grantpt));
     match unsafe { raw::grantpt(master.as_raw_fd()) } {
         0 => Ok(()),
         _ => Err(io::Error::last_os_error()),
@@ -107,6 +242,23 @@ pub fn unlockpt<T>(master: &mut T) -> io::Result<()> where T: AsRawFd {
     }
 }
 
+/// Check if the slave of `master` is locked, i.e. `unlockpt()` was not called yet
+#[cfg(target_os = "linux")]
+pub fn is_locked<T>(master: &T) -> io::Result<bool> where T: AsRawFd {
+    let mut locked: c_int = 0;
+    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTLCK as c_int, &mut locked) } {
+        0 => Ok(locked != 0),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Check if the slave of `master` is locked (unsupported on this platform)
+#[cfg(not(target_os = "linux"))]
+pub fn is_locked<T>(_master: &T) -> io::Result<bool> where T: AsRawFd {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "No TIOCGPTLCK on this platform"))
+}
+
+/// Get the number of the slave of `master`, which is unique among the opened PTYs
 pub fn ptsindex<T>(master: &mut T) -> io::Result<u32> where T: AsRawFd {
     let mut idx: c_uint = 0;
     match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTN as c_int, &mut idx) } {
@@ -115,31 +267,143 @@ pub fn ptsindex<T>(master: &mut T) -> io::Result<u32> where T: AsRawFd {
     }
 }
 
+/// Get the path of the slave number `index` from the devpts mounted on `pts_dir`
+pub fn pts_path<T>(pts_dir: &T, index: u32) -> PathBuf where T: AsRef<Path> {
+    pts_dir.as_ref().join(format!("{}", index))
+}
+
 pub fn ptsname<T>(master: &mut T) -> io::Result<PathBuf> where T: AsRawFd {
-    Ok(Path::new(DEV_PTS_PATH).join(format!("{}", try!(ptsindex(master)))))
+    ptsname_at(master, &DEV_PTS_PATH)
+}
+
+pub fn ptsname_at<T, U>(master: &mut T, pts_dir: &U) -> io::Result<PathBuf> where T: AsRawFd, U: AsRef<Path> {
+    Ok(pts_path(pts_dir, try!(ptsindex(master))))
 }
 
 /// Thread-safe (i.e. reentrant) version of `openpty(3)`
+///
+/// Concurrent calls always get distinct PTYs: the slave path comes from the master itself
+/// (`TIOCGPTN` ioctl) instead of the static buffer of `ptsname(3)`, and all the file descriptors
+/// are opened with `O_CLOEXEC` to not leak into a process spawned by another thread.
 pub fn openpty(termp: Option<&Termios>, winp: Option<&WinSize>) -> io::Result<Pty> {
-    let mut master = try!(getpt());
-    try!(grantpt(&mut master));
+    let master = try!(getpt());
+    openpty_from(master, &DEV_PTS_PATH, termp, winp)
+}
+
+/// Same as `openpty()` but use the devpts mounted on `pts_dir` (e.g. a private instance)
+///
+/// The master is then opened from the `ptmx` of this directory.
+pub fn openpty_at(pts_dir: &Path, termp: Option<&Termios>, winp: Option<&WinSize>) -> io::Result<Pty> {
+    let master = try!(getpt_at(&pts_dir.join("ptmx")));
+    openpty_from(master, &pts_dir, termp, winp)
+}
+
+fn openpty_from<T>(mut master: File, pts_dir: &T, termp: Option<&Termios>, winp: Option<&WinSize>) ->
+        io::Result<Pty> where T: AsRef<Path> {
+    // grantpt(3) is useless with a devpts mounted with the right options (e.g. newinstance)
+    // but may still fail in some sandboxes: only the slave opening is relevant
+    let granted = grantpt(&mut master);
     try!(unlockpt(&mut master));
-    let name = try!(ptsname(&mut master));
-    let slave = try!(open_noctty(&name));
+    let name = try!(ptsname_at(&mut master, pts_dir));
+    let slave = match open_peer(&master) {
+        Ok(s) => s,
+        // Fallback for Linux < 4.13
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTTY) || e.raw_os_error() == Some(libc::EINVAL) =>
+            match open_noctty(&name) {
+                Ok(s) => s,
+                Err(e) => return Err(match granted {
+                    Err(ge) if e.kind() == io::ErrorKind::PermissionDenied => ge,
+                    _ => e,
+                }),
+            },
+        Err(e) => return Err(e),
+    };
 
-    match termp {
-        Some(t) => try!(tcsetattr(slave.as_raw_fd(), termios::TCSAFLUSH, &t)),
-        None => {}
+    if let Some(t) = termp {
+        try!(tcsetattr(slave.as_raw_fd(), termios::TCSAFLUSH, t));
     }
-    match winp {
-        Some(w) => try!(set_winsize(&slave, w)),
-        None => {}
+    if let Some(w) = winp {
+        try!(set_winsize(&slave, w));
     }
 
     // TODO: Add signal handler for SIGWINCH
     Ok(Pty{
-        master: master,
-        slave: slave,
+        master,
+        slave,
         path: name,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn is_cloexec<T>(fd: &T) -> bool where T: AsRawFd {
+        unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) & libc::FD_CLOEXEC != 0 }
+    }
+
+    #[test]
+    fn cloexec() {
+        let pty = openpty(None, None).unwrap();
+        assert!(is_cloexec(&pty.master));
+        assert!(is_cloexec(&pty.slave));
+        assert!(is_cloexec(&open_slave(&pty.path).unwrap()));
+    }
+
+    #[test]
+    fn cloexec_fallback() {
+        // Same as a file opened by a kernel ignoring O_CLOEXEC
+        let fd = unsafe { libc::open(b"/dev/null\0".as_ptr() as *const _, libc::O_RDONLY) };
+        assert!(fd != -1);
+        let file = unsafe { File::from_raw_fd(fd) };
+        assert!(!is_cloexec(&file));
+        assert!(is_cloexec(&ensure_cloexec(file).unwrap()));
+    }
+
+    #[test]
+    fn locked() {
+        let mut master = getpt().unwrap();
+        assert!(is_locked(&master).unwrap());
+        unlockpt(&mut master).unwrap();
+        assert!(!is_locked(&master).unwrap());
+    }
+
+    #[test]
+    fn peer() {
+        let mut master = getpt().unwrap();
+        // The slave can't be opened before being unlocked
+        assert!(open_peer(&master).is_err());
+        unlockpt(&mut master).unwrap();
+        let mut slave = open_peer(&master).unwrap();
+        assert!(is_cloexec(&slave));
+        slave.write_all(b"hi").unwrap();
+        let mut buf = [0; 2];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+
+    #[test]
+    fn exhausted() {
+        let err = map_exhausted(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert!(is_exhausted(&err));
+        assert!(err.to_string().starts_with("PTY allocation exhausted"));
+        assert!(!is_exhausted(&map_exhausted(io::Error::from_raw_os_error(libc::EACCES))));
+        assert!(!is_exhausted(&io::Error::new(io::ErrorKind::Other, "other")));
+    }
+
+
+    #[test]
+    fn winsize() {
+        let ws = WinSize::from((132, 43));
+        assert_eq!((ws.cols(), ws.rows()), (132, 43));
+        assert_eq!(ws.cols_rows(), (132, 43));
+        assert_eq!(WinSize::default().cols_rows(), (80, 24));
+        let pty = openpty(None, Some(&ws)).unwrap();
+        assert_eq!(get_winsize(&pty.master).unwrap(), ws);
+        set_winsize(&pty.master, &WinSize::default()).unwrap();
+        assert_eq!(get_winsize(&pty.slave).unwrap(), WinSize::default());
+    }
+
+}
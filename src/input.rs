@@ -0,0 +1,159 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use scan::{find, partial_len, utf8_partial_len};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Bracketed paste boundaries, sent by the terminal when enabled by the application
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Hook called with the forwarded input and whether it is pasted content (cf.
+/// `TtyClientBuilder::on_input()`)
+pub type InputHook = Box<FnMut(&[u8], bool) + Send>;
+
+// Called with the input as read, including the paste boundaries (e.g. to echo it)
+pub type RawInputHook = Box<FnMut(&[u8]) + Send>;
+
+// Split the input according to the bracketed paste boundaries
+struct PasteSplitter {
+    in_paste: bool,
+    // Possible beginning of a boundary or of a character
+    pending: Vec<u8>,
+}
+
+impl PasteSplitter {
+    fn new() -> PasteSplitter {
+        PasteSplitter {
+            in_paste: false,
+            pending: Vec::new(),
+        }
+    }
+
+    // Call `hook` for each chunk of `data`, without the boundaries nor incomplete UTF-8 characters
+    fn feed<F>(&mut self, data: &[u8], hook: &mut F) where F: FnMut(&[u8], bool) {
+        let mut buf = mem::take(&mut self.pending);
+        buf.extend_from_slice(data);
+        let mut pos = 0;
+        loop {
+            let boundary = if self.in_paste { PASTE_END } else { PASTE_START };
+            match find(&buf[pos..], boundary) {
+                Some(i) => {
+                    if i > 0 {
+                        hook(&buf[pos..pos + i], self.in_paste);
+                    }
+                    pos += i + boundary.len();
+                    self.in_paste = !self.in_paste;
+                },
+                None => {
+                    // Hold back a boundary or a character split across reads
+                    let mut end = buf.len() - partial_len(&buf[pos..], boundary);
+                    end -= utf8_partial_len(&buf[pos..end]);
+                    if end > pos {
+                        hook(&buf[pos..end], self.in_paste);
+                    }
+                    self.pending = buf[end..].to_vec();
+                    return;
+                },
+            }
+        }
+    }
+
+    fn finish<F>(&mut self, hook: &mut F) where F: FnMut(&[u8], bool) {
+        if !self.pending.is_empty() {
+            hook(&self.pending, self.in_paste);
+            self.pending.clear();
+        }
+    }
+}
+
+/// Call all the `raw_hooks` and `hooks` with the data read from `tap` until its end
+///
+/// The `raw_hooks` get the data right away, whereas the `hooks` only get it once the paste
+/// boundaries are removed, which may hold back a possible beginning of a boundary or an
+/// incomplete UTF-8 character until the next read.
+pub fn spawn_input_tap(mut raw_hooks: Vec<RawInputHook>, hooks: Vec<Arc<Mutex<InputHook>>>, mut tap: File) ->
+        JoinHandle<()> {
+    thread::spawn(move || {
+        let mut splitter = PasteSplitter::new();
+        let mut call = |data: &[u8], paste: bool| {
+            for hook in hooks.iter() {
+                if let Ok(mut hook) = hook.lock() {
+                    (*hook)(data, paste);
+                }
+            }
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match tap.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    for hook in raw_hooks.iter_mut() {
+                        hook(&buf[..n]);
+                    }
+                    splitter.feed(&buf[..n], &mut call);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+        splitter.finish(&mut call);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&[u8]]) -> Vec<(Vec<u8>, bool)> {
+        let mut out = Vec::new();
+        let mut splitter = PasteSplitter::new();
+        {
+            let mut hook = |data: &[u8], paste: bool| out.push((data.to_vec(), paste));
+            for chunk in chunks {
+                splitter.feed(chunk, &mut hook);
+            }
+            splitter.finish(&mut hook);
+        }
+        out
+    }
+
+    #[test]
+    fn paste() {
+        assert_eq!(split(&[b"a\x1b[200~b\x1b[201~c"]),
+                   vec![(b"a".to_vec(), false), (b"b".to_vec(), true), (b"c".to_vec(), false)]);
+    }
+
+    #[test]
+    fn split_boundary() {
+        assert_eq!(split(&[b"a\x1b[2", b"00~b\x1b[201", b"~"]),
+                   vec![(b"a".to_vec(), false), (b"b".to_vec(), true)]);
+    }
+
+    #[test]
+    fn unfinished_escape() {
+        assert_eq!(split(&[b"a\x1b"]), vec![(b"a".to_vec(), false), (b"\x1b".to_vec(), false)]);
+    }
+
+    #[test]
+    fn split_utf8() {
+        let text = "\u{e9}t\u{e9}";
+        let (first, second) = text.as_bytes().split_at(1);
+        assert_eq!(split(&[first, second]), vec![(text.as_bytes().to_vec(), false)]);
+    }
+}
@@ -12,73 +12,599 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+// The libc types (e.g. c_int) are only aliases of the core::ffi ones with a recent compiler
+#![allow(clippy::incompatible_msrv)]
+
 #[macro_use]
 extern crate chan;
 
 extern crate chan_signal;
 extern crate fd;
+#[cfg(feature = "flate2")]
+extern crate flate2;
 extern crate libc;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "signal-hook")]
+extern crate signal_hook;
 extern crate termios;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 use chan_signal::Signal;
-use fd::{Pipe, set_flags, splice_loop, unset_append_flag};
-use ffi::{get_winsize, openpty, set_winsize};
-use libc::c_int;
-use std::fs::File;
-use std::io;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
-use std::os::unix::process::CommandExt;
+use fd::{Pipe, set_flags};
+use ffi::{WinSize, get_winsize, open_slave, openpty, openpty_at, set_winsize};
+use input::{RawInputHook, spawn_input_tap};
+use proxy::{Proxy, new_pipe, poll_one};
+use record::{Recorder, spawn_recorder};
+use libc::{c_int, pid_t};
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
-use termios::{Termios, tcsetattr};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use fault::{tcsetattr, unset_append_flag};
+use termios::{Termios, cc_t, cfgetospeed, cfmakeraw, cfsetispeed, cfsetospeed, speed_t, tcflag_t, tcflush};
+use termios::os::linux;
 
+pub use bridge::{Bridge, bridge};
+pub use control::{ControlMsg, ControlParser};
 pub use fd::FileDesc;
+pub use input::InputHook;
+pub use ffi::Pty;
+pub use play::Player;
+pub use pool::{BufferPool, TtyServerPool};
+pub use slave::SlaveHandle;
+pub use writer::MasterWriter;
 
+mod bridge;
+mod control;
+#[cfg(feature = "regex")]
+mod expect;
+mod fault;
 pub mod ffi;
+mod input;
+mod play;
+mod pool;
+mod proxy;
+mod record;
+mod scan;
+mod slave;
+mod writer;
+
+#[cfg(feature = "regex")]
+pub use expect::{Captures, Expect};
+#[cfg(feature = "metrics")]
+pub use proxy::TtyStats;
+#[cfg(feature = "fault-injection")]
+pub use fault::{Syscall, clear_fault, inject_fault};
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+pub use record::Codec;
+pub use record::{OnLimit, SessionMeta};
+
+// Interval between two checks of the foreground process group
+const FOREGROUND_POLL_MS: u64 = 10;
+
+// Soft reset, main screen, visible cursor, no mouse tracking nor bracketed paste
+const RESET_DISPLAY: &[u8] = b"\x1b[!p\x1b[?1049l\x1b[?25h\x1b[?1000l\x1b[?1002l\x1b[?1006l\x1b[?2004l";
+
+// Last SessionId
+static SESSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// TERM of the spawned processes (cf. set_default_term())
+static DEFAULT_TERM: Mutex<Option<String>> = Mutex::new(None);
+
+// Supported terminal speeds (cf. termios(3))
+const SPEEDS: [(u32, speed_t); 31] = [
+    (0, termios::B0), (50, termios::B50), (75, termios::B75), (110, termios::B110),
+    (134, termios::B134), (150, termios::B150), (200, termios::B200), (300, termios::B300),
+    (600, termios::B600), (1200, termios::B1200), (1800, termios::B1800),
+    (2400, termios::B2400), (4800, termios::B4800), (9600, termios::B9600),
+    (19200, termios::B19200), (38400, termios::B38400), (57600, linux::B57600),
+    (115200, linux::B115200), (230400, linux::B230400), (460800, linux::B460800),
+    (500000, linux::B500000), (576000, linux::B576000), (921600, linux::B921600),
+    (1000000, linux::B1000000), (1152000, linux::B1152000), (1500000, linux::B1500000),
+    (2000000, linux::B2000000), (2500000, linux::B2500000), (3000000, linux::B3000000),
+    (3500000, linux::B3500000), (4000000, linux::B4000000),
+];
 
 pub struct TtyServer {
     master: File,
     slave: Option<File>,
     path: PathBuf,
+    reaper: Option<Reaper>,
+    // Shared by the MasterWriters
+    write_lock: Arc<Mutex<()>>,
+    // Held by the bound TtyClients
+    clients: Arc<()>,
+    // Duplicates of the slave may be held by processes (cf. `spawn_keep_slave()`)
+    slave_shared: bool,
+    id: SessionId,
+}
+
+struct ReapState {
+    pid: Option<pid_t>,
+    status: Option<ExitStatus>,
+}
+
+struct Reaper {
+    state: Arc<Mutex<ReapState>>,
+    // Automatically send an event when dropped
+    _stop: chan::Sender<()>,
 }
 
 pub struct TtyClient {
+    // Fields are dropped in declaration order: close the peer before the master to not hang up
+    // the slave side while the last output is forwarded
+    peer: FileDesc,
+    // Only set when the peer output is not the same file descriptor as its input
+    peer_out: Option<FileDesc>,
+    peer_status: Option<c_int>,
     // Need to keep the master file descriptor open
     #[allow(dead_code)]
     master: FileDesc,
     master_status: Option<c_int>,
-    peer: FileDesc,
-    peer_status: Option<c_int>,
-    termios_orig: Termios,
-    do_flush: Arc<AtomicBool>,
+    winsize_orig: Option<WinSize>,
+    // Either the peer input or output, whichever is a TTY
+    peer_tty: RawFd,
+    termios_orig: Option<Termios>,
+    config: BindConfig,
+    sigwinch_handler: Option<chan::Receiver<Signal>>,
+    resize_trigger: Option<chan::Receiver<()>>,
+    on_resize: Option<Arc<Mutex<ResizeHook>>>,
+    on_input: Option<Arc<Mutex<InputHook>>>,
+    other_signals: Option<chan::Sender<Signal>>,
+    #[cfg(feature = "signal-hook")]
+    signal_hook: Option<signal_hook::iterator::Handle>,
+    proxy: Proxy,
     flush_event: Receiver<()>,
+    // Set once the master output ended
+    child_gone: Arc<AtomicBool>,
+    max_duration: Option<(Instant, bool)>,
+    // Set once the max_duration is exceeded
+    expired: Arc<AtomicBool>,
+    proxy_threads: Vec<JoinHandle<()>>,
+    // Stopped by pause() until resume()
+    paused: bool,
+    output_recorder: Option<Arc<Mutex<Recorder>>>,
+    // Forward with read(2) and write(2) instead of splice(2)
+    buffer_pool: Option<BufferPool>,
     // Automatically send an event when dropped
     _stop: chan::Sender<()>,
+    // Set if bound through a TtyServer
+    _server_ref: Option<Arc<()>>,
+    id: SessionId,
+}
+
+// How the data is forwarded in one direction
+enum Forward {
+    Splice(Pipe),
+    Copy(BufferPool),
+}
+
+// Restore a TTY configuration when dropped
+struct TermiosGuard {
+    fd: RawFd,
+    termios: Termios,
+}
+
+impl Drop for TermiosGuard {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, termios::TCSANOW, &self.termios);
+    }
+}
+
+/// Iterator over the output of a TTY (cf. `TtyServer::output_chunks()`)
+pub struct OutputChunks<'a> {
+    master: &'a File,
+    done: bool,
+}
+
+/// Saved configuration of a peer TTY
+#[derive(Clone, Copy)]
+pub struct PeerState {
+    termios: Termios,
+    flags: c_int,
+}
+
+/// Copy of a TTY configuration, e.g. to replicate a local terminal on a remote TTY
+///
+/// This is serializable with the `serde` feature. The speeds are part of the control flags.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TermiosSnapshot {
+    /// Input flags (`c_iflag`)
+    pub iflag: tcflag_t,
+    /// Output flags (`c_oflag`)
+    pub oflag: tcflag_t,
+    /// Control flags (`c_cflag`)
+    pub cflag: tcflag_t,
+    /// Local flags (`c_lflag`)
+    pub lflag: tcflag_t,
+    /// Control characters (`c_cc`)
+    pub cc: Vec<cc_t>,
+}
+
+impl<'a> From<&'a Termios> for TermiosSnapshot {
+    fn from(termios: &'a Termios) -> TermiosSnapshot {
+        TermiosSnapshot {
+            iflag: termios.c_iflag,
+            oflag: termios.c_oflag,
+            cflag: termios.c_cflag,
+            lflag: termios.c_lflag,
+            cc: termios.c_cc.to_vec(),
+        }
+    }
+}
+
+impl TermiosSnapshot {
+    // Overwrite the flags and the control characters of `termios`
+    fn apply_to(&self, termios: &mut Termios) {
+        termios.c_iflag = self.iflag;
+        termios.c_oflag = self.oflag;
+        termios.c_cflag = self.cflag;
+        termios.c_lflag = self.lflag;
+        let len = cmp::min(self.cc.len(), termios.c_cc.len());
+        termios.c_cc[..len].copy_from_slice(&self.cc[..len]);
+    }
+}
+
+/// Identifier of a `TtyServer` or a `TtyClient`, unique in the process (e.g. to correlate logs)
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SessionId(usize);
+
+impl SessionId {
+    fn next() -> SessionId {
+        SessionId(SESSION_COUNTER.fetch_add(1, Relaxed) + 1)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a `TtyClient` stopped forwarding (cf. `TtyClient::end_reason()`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EndReason {
+    /// All the slave file descriptors are closed (e.g. the process exited)
+    ChildExit,
+    /// The `TtyClientBuilder::max_duration()` is exceeded
+    MaxDuration,
+    /// An error occurred (cf. `TtyClient::take_error()`)
+    Error,
+    /// The peer is closed, including its file descriptors or the reader of its output
+    PeerClosed,
+}
+
+/// Error wrapped in an `io::Error` when the peer TTY can't be configured by a new client
+///
+/// The peer configuration is then rolled back. The `io::Error` keeps the kind of the cause,
+/// whose OS error is still available through `raw_os_error()`.
+#[derive(Debug)]
+pub struct PeerSetupError {
+    cause: io::Error,
+}
+
+impl PeerSetupError {
+    /// Get the OS error of the cause, if any
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.cause.raw_os_error()
+    }
+}
+
+impl fmt::Display for PeerSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to configure the peer TTY: {}", self.cause)
+    }
+}
+
+impl Error for PeerSetupError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// Software flow control (i.e. XON/XOFF) handling
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlowControl {
+    /// Keep the peer TTY configuration, which may handle XON/XOFF itself (default)
+    Peer,
+    /// Forward XON/XOFF to the slave TTY to pause and resume the process output
+    Slave,
+    /// Forward XON/XOFF to the process as any other byte
+    Disabled,
+}
+
+/// Behavior when the append flag of a file descriptor can't be unset
+///
+/// The proxy can't write to a file descriptor with the append flag, which is then unset during
+/// the client lifetime. This flag belongs to the open file description, which is shared with
+/// the duplicated file descriptors (e.g. `dup(2)` or inherited ones): they also see the change.
+/// Only the append flag is restored, and only if it was set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AppendFlagPolicy {
+    /// Ignore the errors unrelated to an invalid file descriptor (default)
+    Lenient,
+    /// Abort the client creation on any error
+    Strict,
+}
+
+/// How a process is spawned with `TtyServer::spawn_with()`
+///
+/// This covers its session, its standard streams and TTY environment, the extra file descriptors
+/// it inherits, and the hooks run just before its exec.
+pub struct SpawnOptions {
+    // Source in the parent and destination in the child
+    fds: Vec<(RawFd, RawFd)>,
+    setsid: bool,
+    close_stdin: bool,
+    stderr: Option<Stdio>,
+    export_size: bool,
+    term: Option<String>,
+    hooks: Vec<Box<FnMut() -> io::Result<()> + Send + Sync>>,
+}
+
+/// When to apply a TTY configuration change (cf. `tcsetattr(3)`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SetAction {
+    /// Change immediately (`TCSANOW`)
+    Now,
+    /// Change once the pending output is written (`TCSADRAIN`)
+    Drain,
+    /// Same as `Drain` but also discard the unread input (`TCSAFLUSH`)
+    Flush,
+}
+
+impl SetAction {
+    fn as_raw(&self) -> c_int {
+        match *self {
+            SetAction::Now => termios::TCSANOW,
+            SetAction::Drain => termios::TCSADRAIN,
+            SetAction::Flush => termios::TCSAFLUSH,
+        }
+    }
+}
+
+/// How the TTY size of a connection follows the remote terminal (cf. `serve_tcp_with()`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpResize {
+    /// Keep the default size (i.e. 80x24)
+    Fixed,
+    /// Apply the control messages sent in-band by the remote end (cf. `ControlMsg::to_bytes()`)
+    ///
+    /// The messages are removed from the input, which then goes through a dedicated thread.
+    Control,
+}
+
+/// Output processing of a slave TTY (cf. `TtyServer::set_output_processing()`)
+///
+/// The delays are only relevant for the programs emulating a real terminal, which may rely on
+/// them (e.g. fill characters).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputProcessing {
+    flags: termios::tcflag_t,
+    nl_delay: u8,
+    cr_delay: u8,
+    tab_delay: u8,
+}
+
+impl OutputProcessing {
+    /// No processing at all, i.e. the output is not changed
+    pub fn new() -> OutputProcessing {
+        OutputProcessing {
+            flags: 0,
+            nl_delay: 0,
+            cr_delay: 0,
+            tab_delay: 0,
+        }
+    }
+
+    fn flag(mut self, flag: termios::tcflag_t, enable: bool) -> OutputProcessing {
+        if enable {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Output a new line as a carriage return and a new line (ONLCR)
+    pub fn onlcr(self, enable: bool) -> OutputProcessing {
+        self.flag(linux::ONLCR, enable)
+    }
+
+    /// Output a carriage return as a new line (OCRNL)
+    pub fn ocrnl(self, enable: bool) -> OutputProcessing {
+        self.flag(linux::OCRNL, enable)
+    }
+
+    /// Don't output a carriage return at the first column (ONOCR)
+    pub fn onocr(self, enable: bool) -> OutputProcessing {
+        self.flag(linux::ONOCR, enable)
+    }
+
+    /// Don't output a carriage return, a new line being enough (ONLRET)
+    pub fn onlret(self, enable: bool) -> OutputProcessing {
+        self.flag(linux::ONLRET, enable)
+    }
+
+    /// New line delay style, from 0 (none) to 1 (NLDLY)
+    pub fn nl_delay(mut self, style: u8) -> OutputProcessing {
+        self.nl_delay = style;
+        self
+    }
+
+    /// Carriage return delay style, from 0 (none) to 3 (CRDLY)
+    pub fn cr_delay(mut self, style: u8) -> OutputProcessing {
+        self.cr_delay = style;
+        self
+    }
+
+    /// Horizontal tab delay style, from 0 (none) to 3, which expands the tabs to spaces (TABDLY)
+    pub fn tab_delay(mut self, style: u8) -> OutputProcessing {
+        self.tab_delay = style;
+        self
+    }
+
+    // Get the c_oflag bits, or None if a delay style is invalid
+    fn as_raw(&self) -> Option<termios::tcflag_t> {
+        let nl = [linux::NL0, linux::NL1];
+        let cr = [linux::CR0, linux::CR1, linux::CR2, linux::CR3];
+        let tab = [linux::TAB0, linux::TAB1, linux::TAB2, linux::TAB3];
+        let delays = match (nl.get(self.nl_delay as usize), cr.get(self.cr_delay as usize),
+                            tab.get(self.tab_delay as usize)) {
+            (Some(nl), Some(cr), Some(tab)) => nl | cr | tab,
+            _ => return None,
+        };
+        let flags = self.flags | delays;
+        // Without OPOST, the output is not processed at all
+        Some(if flags != 0 { flags | termios::OPOST } else { 0 })
+    }
+}
+
+impl Default for OutputProcessing {
+    fn default() -> OutputProcessing {
+        OutputProcessing::new()
+    }
+}
+
+/// Configure a `TtyClient` before binding it
+pub struct TtyClientBuilder {
+    config: BindConfig,
+    output_recorder: Option<Recorder>,
+    buffer_pool: Option<BufferPool>,
+    resize_trigger: Option<chan::Receiver<()>>,
+    on_resize: Option<ResizeHook>,
+    on_input: Option<InputHook>,
+    other_signals: Option<chan::Sender<Signal>>,
+    session_meta: Option<SessionMeta>,
+    keep_master_flags: bool,
+    restore_winsize: bool,
+    max_duration: Option<(Duration, bool)>,
+}
+
+type ResizeHook = Box<FnMut(WinSize) + Send>;
+
+// Needed to bind a new peer
+#[derive(Clone, Copy)]
+struct BindConfig {
+    flow_control: FlowControl,
+    append_flag: AppendFlagPolicy,
+    input: bool,
+    output: bool,
+    restore_action: SetAction,
+    pipe_size: Option<usize>,
+    local_echo: bool,
+    local_echo_crlf: bool,
+    watchdog: Option<Duration>,
+    reset_display: bool,
+    resize_debounce: Option<Duration>,
+    translate_cr: bool,
 }
 
 impl TtyServer {
     /// Create a new TTY with the same configuration (termios and size) as the `template` TTY
+    ///
+    /// Without template, the TTY keeps the kernel default size (i.e. 0x0), which is not set to a
+    /// standard one unless created with `new_default()`.
+    ///
+    /// This can be called concurrently from multiple threads (cf. `ffi::openpty()`).
     pub fn new<T>(template: Option<&T>) -> io::Result<TtyServer> where T: AsRawFd {
         // Native runtime does not support RtioTTY::get_winsize()
         let pty = match template {
             Some(t) => try!(openpty(Some(&try!(Termios::from_fd(t.as_raw_fd()))), Some(&try!(get_winsize(t))))),
             None => try!(openpty(None, None)),
         };
+        Ok(TtyServer::from_pty(pty))
+    }
+
+    /// Get the identifier of this server
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Same as `new()` without template, but with the `WinSize` default size (i.e. 80x24)
+    pub fn new_default() -> io::Result<TtyServer> {
+        Ok(TtyServer::from_pty(try!(openpty(None, Some(&WinSize::default())))))
+    }
+
+    /// Create a new TTY from the devpts mounted on `pts_dir` (cf. `new()`)
+    pub fn new_at<T>(pts_dir: &Path, template: Option<&T>) -> io::Result<TtyServer> where T: AsRawFd {
+        let pty = match template {
+            Some(t) => try!(openpty_at(pts_dir, Some(&try!(Termios::from_fd(t.as_raw_fd()))), Some(&try!(get_winsize(t))))),
+            None => try!(openpty_at(pts_dir, None, None)),
+        };
+        Ok(TtyServer::from_pty(pty))
+    }
+
+    /// Create a new TTY in raw mode (i.e. without any input or output processing)
+    ///
+    /// This is useful to transfer binary data: neither echo nor character translation is done.
+    pub fn new_raw(winsize: Option<&WinSize>) -> io::Result<TtyServer> {
+        let pty = try!(openpty(None, winsize));
+        let mut termios_raw = try!(Termios::from_fd(pty.slave.as_raw_fd()));
+        cfmakeraw(&mut termios_raw);
+        try!(tcsetattr(pty.slave.as_raw_fd(), termios::TCSANOW, &termios_raw));
+        Ok(TtyServer::from_pty(pty))
+    }
 
-        Ok(TtyServer {
+    /// Manage an existing PTY
+    pub fn from_pty(pty: Pty) -> TtyServer {
+        TtyServer {
             master: pty.master,
             slave: Some(pty.slave),
             path: pty.path,
+            reaper: None,
+            write_lock: Arc::new(Mutex::new(())),
+            clients: Arc::new(()),
+            slave_shared: false,
+            id: SessionId::next(),
+        }
+    }
+
+    /// Manage an existing PTY from its raw file descriptors (e.g. inherited from a parent)
+    ///
+    /// The `master` and `slave` file descriptors are owned by the returned `TtyServer`, and
+    /// `path` is the slave TTY path.
+    ///
+    /// # Safety
+    ///
+    /// `master` must be an open PTY master and `slave` an open file descriptor of its slave.
+    /// Both are closed when the server is dropped: they must not be owned nor closed by anything
+    /// else (e.g. a `File` or an `OwnedFd`).
+    pub unsafe fn from_raw_slave(master: RawFd, slave: RawFd, path: PathBuf) -> TtyServer {
+        TtyServer::from_pty(Pty {
+            master: File::from_raw_fd(master),
+            slave: File::from_raw_fd(slave),
+            path,
         })
     }
 
     /// Bind the peer TTY with the server TTY
     ///
+    /// The process can be spawned before or after the client is bound. Until then, its output is
+    /// kept by the TTY, which is only read by the client proxy: nothing is lost, but the process
+    /// blocks once the TTY buffer is full.
+    ///
+    /// The client uses its own duplicate of the master, hence it keeps forwarding if the server
+    /// is dropped first.
+    ///
     /// The sigwinch_handler must handle the SIGWINCH signal to update the TTY window size.
     /// This handler can be created with `chan_signal::notify(&[Signal::WINCH])` from the
     /// chan_signal crate.
@@ -86,8 +612,155 @@ impl TtyServer {
     /// Any and all threads spawned must come after the first call to chan_signal::notify!
     pub fn new_client<T>(&self, peer: T, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
             io::Result<TtyClient> where T: AsRawFd + IntoRawFd {
-        let master = FileDesc::new(self.master.as_raw_fd(), false);
-        TtyClient::new(master, peer, sigwinch_handler)
+        self.new_client_with(TtyClientBuilder::new(), peer, sigwinch_handler)
+    }
+
+    /// Bind the peer TTY with the server TTY according to the `builder` configuration
+    ///
+    /// See `new_client()` for the sigwinch_handler requirements.
+    pub fn new_client_with<T>(&self, builder: TtyClientBuilder, peer: T,
+                              sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+            io::Result<TtyClient> where T: AsRawFd + IntoRawFd {
+        // The client may outlive the server
+        let master = try!(dup_cloexec(self.master.as_raw_fd()));
+        let mut client = try!(builder.build(master, peer, sigwinch_handler));
+        client._server_ref = Some(self.clients.clone());
+        Ok(client)
+    }
+
+    /// Get back the raw PTY to manage it manually
+    ///
+    /// This fails if the slave was already taken or spawned.
+    pub fn into_pty(mut self) -> io::Result<Pty> {
+        match self.slave.take() {
+            Some(slave) => Ok(Pty {
+                master: self.master,
+                slave,
+                path: self.path,
+            }),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "No TTY slave")),
+        }
+    }
+
+    /// Set the input and output speed of the TTY (e.g. 115200 bauds for a serial line emulation)
+    ///
+    /// Only the standard speeds are supported, otherwise an `InvalidInput` error is returned.
+    pub fn set_speed(&self, baud: u32) -> io::Result<()> {
+        let speed = match SPEEDS.iter().find(|&&(b, _)| b == baud) {
+            Some(&(_, s)) => s,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported speed")),
+        };
+        // The slave TTY configuration is reachable through the master
+        let mut termios_slave = try!(Termios::from_fd(self.master.as_raw_fd()));
+        try!(cfsetispeed(&mut termios_slave, speed));
+        try!(cfsetospeed(&mut termios_slave, speed));
+        tcsetattr(self.master.as_raw_fd(), termios::TCSANOW, &termios_slave)
+    }
+
+    /// Get the output speed of the TTY in bauds
+    pub fn speed(&self) -> io::Result<u32> {
+        let termios_slave = try!(Termios::from_fd(self.master.as_raw_fd()));
+        let speed = cfgetospeed(&termios_slave);
+        match SPEEDS.iter().find(|&&(_, s)| s == speed) {
+            Some(&(b, _)) => Ok(b),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown speed")),
+        }
+    }
+
+    /// Replace the output processing of the TTY, e.g. to translate the new lines of a program
+    ///
+    /// An invalid delay style returns an `InvalidInput` error.
+    pub fn set_output_processing(&self, processing: OutputProcessing) -> io::Result<()> {
+        let oflag = match processing.as_raw() {
+            Some(f) => f,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid delay style")),
+        };
+        let mask = termios::OPOST | linux::ONLCR | linux::OCRNL | linux::ONOCR | linux::ONLRET |
+            linux::NLDLY | linux::CRDLY | linux::TABDLY;
+        // The slave TTY configuration is reachable through the master
+        let mut termios_slave = try!(Termios::from_fd(self.master.as_raw_fd()));
+        termios_slave.c_oflag = (termios_slave.c_oflag & !mask) | oflag;
+        tcsetattr(self.master.as_raw_fd(), termios::TCSADRAIN, &termios_slave)
+    }
+
+    /// Configure the slave TTY as described by `snapshot` (cf. `TtyClient::peer_termios_snapshot()`)
+    ///
+    /// The control characters missing from `snapshot` are left unchanged.
+    pub fn apply_termios_snapshot(&self, snapshot: &TermiosSnapshot) -> io::Result<()> {
+        // The slave TTY configuration is reachable through the master
+        let mut termios_slave = try!(Termios::from_fd(self.master.as_raw_fd()));
+        snapshot.apply_to(&mut termios_slave);
+        tcsetattr(self.master.as_raw_fd(), termios::TCSANOW, &termios_slave)
+    }
+
+    /// Set the TTY window size to the one of `src` (e.g. a TTY reflecting a remote terminal)
+    ///
+    /// The master and the slave share the same size, which is then seen by the processes already
+    /// running (with a SIGWINCH) as well as the ones spawned later, even on a reopened slave.
+    pub fn sync_winsize_from<T>(&self, src: &T) -> io::Result<()> where T: AsRawFd {
+        set_winsize(&self.master, &try!(get_winsize(src)))
+    }
+
+    /// Change the slave TTY configuration with `change` while running `run`
+    ///
+    /// The original configuration is restored afterwards, even if `run` panics, e.g. to disable
+    /// ECHO while reading a password.
+    pub fn with_slave_termios<F, G, R>(&self, change: F, run: G) -> io::Result<R>
+            where F: FnOnce(&mut Termios), G: FnOnce() -> R {
+        let fd = self.master.as_raw_fd();
+        let orig = try!(Termios::from_fd(fd));
+        let mut termios = orig;
+        change(&mut termios);
+        try!(tcsetattr(fd, termios::TCSANOW, &termios));
+        let _restore = TermiosGuard {
+            fd,
+            termios: orig,
+        };
+        Ok(run())
+    }
+
+    /// Iterate over the TTY output until all the slave file descriptors are closed
+    ///
+    /// Each chunk is the data available at once. This reads the master, which must then not be
+    /// used by a client.
+    pub fn output_chunks<'a>(&'a self) -> OutputChunks<'a> {
+        OutputChunks {
+            master: &self.master,
+            done: false,
+        }
+    }
+
+    /// Get a handle to write to the master from multiple threads (cf. `MasterWriter`)
+    pub fn master_writer(&self) -> io::Result<MasterWriter> {
+        Ok(MasterWriter::new(try!(self.master.try_clone()), self.write_lock.clone()))
+    }
+
+    /// Get a reader and a writer of the master, e.g. to forward the TTY with a custom proxy
+    ///
+    /// Both use their own duplicate of the master, independent of the server lifetime, and can be
+    /// moved to different threads. The reader gets an end of file once all the slave file
+    /// descriptors are closed, and the writer is a `MasterWriter`. The output is only read once:
+    /// the master must then not be used by a client nor read by another reader.
+    pub fn master_halves(&self) -> io::Result<(impl Read + Send, impl Write + Send)> {
+        let reader = MasterReader(try!(self.master.try_clone()));
+        Ok((reader, try!(self.master_writer())))
+    }
+
+    /// Make the master reads and writes return a `WouldBlock` error instead of waiting
+    ///
+    /// This is useful to drive the master from an event loop instead of a `TtyClient`, which
+    /// must then not be bound through this server.
+    pub fn set_master_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        if Arc::strong_count(&self.clients) > 1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "A client is bound to the master"));
+        }
+        let fd = self.master.as_raw_fd();
+        let flags = try!(get_flags(fd));
+        set_flags(fd, if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        })
     }
 
     /// Get the TTY master file descriptor usable by a `TtyClient`
@@ -100,156 +773,3276 @@ impl TtyServer {
         self.slave.take()
     }
 
-    /// Spawn a new process connected to the slave TTY
-    pub fn spawn(&mut self, mut cmd: Command) -> io::Result<Child> {
-        match self.slave.take() {
-            Some(slave) => {
-                // Force new session
-                // TODO: tcsetpgrp
-                cmd.stdin(unsafe { Stdio::from_raw_fd(slave.as_raw_fd()) }).
-                    stdout(unsafe { Stdio::from_raw_fd(slave.as_raw_fd()) }).
-                    // Must close the slave FD to not wait indefinitely the end of the proxy
-                    stderr(unsafe { Stdio::from_raw_fd(slave.into_raw_fd()) }).
-                    // Don't check the error of setsid because it fails if we're the
-                    // process leader already. We just forked so it shouldn't return
-                    // error, but ignore it anyway.
-                    before_exec(|| { let _ = unsafe { libc::setsid() }; Ok(()) }).
-                    spawn()
-            },
-            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "No TTY slave")),
+    /// Borrow the TTY slave, e.g. to configure it before the next `spawn()`
+    ///
+    /// This is `None` once the slave is taken or spawned.
+    pub fn slave(&self) -> Option<&File> {
+        self.slave.as_ref()
+    }
+
+    /// Give a TTY slave file descriptor to be used by the next `spawn()`
+    ///
+    /// The previous slave, if any, is closed. The `slave` should belong to the same PTY as the
+    /// master.
+    pub fn set_slave(&mut self, slave: File) {
+        self.slave = Some(slave);
+    }
+
+    /// Open a new slave file descriptor, e.g. once the previous one was taken or spawned
+    pub fn open_slave(&self) -> io::Result<File> {
+        open_slave(&self.path)
+    }
+
+    // Check if a client or a process still uses the TTY, closing the slave if it may be shared
+    fn is_attached(&mut self) -> bool {
+        if Arc::strong_count(&self.clients) > 1 {
+            return true;
         }
+        if self.slave_shared {
+            // Closing our slave is the only way to know if a spawned process still holds one
+            self.slave = None;
+            self.slave_shared = false;
+        }
+        self.slave.is_none() && !self.slave_hangup_detected()
     }
-}
 
-impl AsRef<Path> for TtyServer {
-    /// Get the server TTY path
-    fn as_ref(&self) -> &Path {
-        self.path.as_ref()
+    // Make the TTY as new for another session, with the `termios` configuration
+    fn reset(&mut self, termios: &Termios) -> io::Result<()> {
+        // Don't share the TTY with a remaining client or process of the previous session
+        if self.is_attached() {
+            return Err(io::Error::new(io::ErrorKind::Other, "TTY still in use"));
+        }
+        if self.slave.is_none() {
+            self.slave = Some(try!(self.open_slave()));
+        }
+        self.reaper = None;
+        self.id = SessionId::next();
+        // Discard the pending input and output of the previous session
+        try!(tcflush(self.master.as_raw_fd(), termios::TCIOFLUSH));
+        try!(tcsetattr(self.master.as_raw_fd(), termios::TCSANOW, termios));
+        set_winsize(&self.master, &WinSize::default())
     }
-}
 
-// Ignore errors
-fn copy_winsize<T, U>(src: &T, dst: &U) where T: AsRawFd, U: AsRawFd {
-    if let Ok(ws) = get_winsize(src) {
-        let _ = set_winsize(dst, &ws);
+    /// Take the TTY slave to read and write it directly (cf. `SlaveHandle`)
+    pub fn take_slave_handle(&mut self) -> Option<SlaveHandle> {
+        self.slave.take().map(SlaveHandle::new)
     }
-}
 
-// TODO: Handle SIGWINCH to dynamically update WinSize
-// TODO: Replace `spawn` with `scoped` and share variables
-impl TtyClient {
-    /// Setup the peer TTY client (e.g. stdio) and bind it to the master TTY server
-    ///
-    /// The sigwinch_handler must handle the SIGWINCH signal to update the TTY window size.
-    /// This handler can be created with `chan_signal::notify(&[Signal::WINCH])` from the
-    /// chan_signal crate.
+    /// Take the TTY slave file descriptor with its ownership tracked by the standard library
+    pub fn take_slave_owned(&mut self) -> Option<OwnedFd> {
+        self.slave.take().map(OwnedFd::from)
+    }
+
+    /// Get the TTY master file descriptor, closing the slave if not already taken
+    pub fn into_master_owned(self) -> OwnedFd {
+        OwnedFd::from(self.master)
+    }
+
+    /// Check if all the slave file descriptors are closed (e.g. the connected processes exited)
     ///
-    /// Any and all threads spawned must come after the first call to chan_signal::notify!
-    pub fn new<T, U>(master: T, peer: U, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
-            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
-        // Setup peer terminal configuration
-        let termios_orig = try!(Termios::from_fd(peer.as_raw_fd()));
-        let mut termios_peer = try!(Termios::from_fd(peer.as_raw_fd()));
-        termios_peer.c_lflag &= !(termios::ECHO | termios::ICANON | termios::ISIG);
-        termios_peer.c_iflag &= !(termios::IGNBRK | termios::ICRNL);
-        termios_peer.c_iflag |= termios::BRKINT;
-        termios_peer.c_cc[termios::VMIN] = 1;
-        termios_peer.c_cc[termios::VTIME] = 0;
-        // XXX: cfmakeraw
-        try!(tcsetattr(peer.as_raw_fd(), termios::TCSAFLUSH, &termios_peer));
-
-        // Create the proxy
-        let do_flush_main = Arc::new(AtomicBool::new(false));
-        let (event_tx, event_rx): (Sender<()>, Receiver<()>) = channel();
-
-        // Master to peer
-        let (m2p_tx, m2p_rx) = match Pipe::new() {
-            Ok(p) => (p.writer, p.reader),
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-        };
-        let do_flush = do_flush_main.clone();
-        let master_fd = master.as_raw_fd();
-        thread::spawn(move || splice_loop(do_flush, None, master_fd, m2p_tx.as_raw_fd()));
-
-        let do_flush = do_flush_main.clone();
-        let peer_fd = peer.as_raw_fd();
-        let peer_status = try!(unset_append_flag(peer_fd));
-        thread::spawn(move || splice_loop(do_flush, None, m2p_rx.as_raw_fd(), peer_fd));
-
-        // Peer to master
-        let (p2m_tx, p2m_rx) = match Pipe::new() {
-            Ok(p) => (p.writer, p.reader),
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-        };
-        let do_flush = do_flush_main.clone();
-        let peer_fd = peer.as_raw_fd();
-        thread::spawn(move || splice_loop(do_flush, None, peer_fd, p2m_tx.as_raw_fd()));
-
-        let do_flush = do_flush_main.clone();
-        let master_fd = master.as_raw_fd();
-        let master_status = try!(unset_append_flag(master_fd));
-        thread::spawn(move || splice_loop(do_flush, Some(event_tx), p2m_rx.as_raw_fd(), master_fd));
-
-        // Handle terminal resizing
-        let (stop_tx, stop_rx) = chan::sync(0);
-        if let Some(signal) = sigwinch_handler {
-            // master and peer FD will be close by TtyClient::drop()
-            let master2 = FileDesc::new(master.as_raw_fd(), false);
-            let peer2 = FileDesc::new(peer.as_raw_fd(), false);
-            thread::spawn(move || {
-                'select: loop {
-                    chan_select! {
-                        signal.recv() -> signal => {
-                            if signal != Some(Signal::WINCH) {
-                                continue 'select;
-                            }
-                            copy_winsize(&peer2, &master2);
-                        },
-                        stop_rx.recv() => {
-                            break;
-                        }
-                    }
-                }
-            });
-        }
+    /// This can only happen once the slave is taken or spawned, and is independent of the proxy
+    /// state of the clients.
+    pub fn slave_hangup_detected(&self) -> bool {
+        is_hangup(&self.master)
+    }
 
-        Ok(TtyClient {
-            master: FileDesc::new(master.into_raw_fd(), true),
-            master_status: master_status,
-            peer: FileDesc::new(peer.into_raw_fd(), true),
-            peer_status: peer_status,
-            termios_orig: termios_orig,
-            do_flush: do_flush_main,
-            flush_event: event_rx,
-            _stop: stop_tx,
-        })
+    /// Spawn a new process connected to the slave TTY
+    ///
+    /// A client can then be bound to forward the process I/O (cf. `new_client()`). If the slave
+    /// was already taken or spawned, a new one is opened (cf. `open_slave()`).
+    pub fn spawn(&mut self, cmd: Command) -> io::Result<Child> {
+        self.spawn_with(cmd, SpawnOptions::new())
     }
 
-    /// Wait until the TTY binding broke (e.g. the connected process exited)
-    pub fn wait(&self) {
-        while !self.do_flush.load(Relaxed) {
-            let _ = self.flush_event.recv();
-        }
+    /// Same as `spawn()` but configure the process according to `options`
+    pub fn spawn_with(&mut self, cmd: Command, options: SpawnOptions) -> io::Result<Child> {
+        try!(options.check());
+        let slave = match self.slave.take() {
+            Some(s) => s,
+            // The slave was taken or already spawned
+            None => try!(self.reopen_slave()),
+        };
+        self.spawn_on(cmd, options, slave)
     }
 
-    /// Update the terminal window size according to the peer
-    pub fn update_winsize(&mut self) {
-        copy_winsize(&self.peer, &self.master);
+    /// Same as `spawn_with()` but keep the slave, e.g. to spawn other processes on the same TTY
+    ///
+    /// The process gets a duplicate of the slave, hence the master never hangs up while the
+    /// server holds it: a client only stops once the slave is taken or the server is dropped.
+    /// A TTY can only be the controlling terminal of one session. Processes spawned in their own
+    /// session (cf. `SpawnOptions::setsid()`) are session leaders without controlling terminal,
+    /// and only the first one acquiring the TTY (e.g. with `TIOCSCTTY`) gets its job control
+    /// signals.
+    pub fn spawn_keep_slave(&mut self, cmd: Command, options: SpawnOptions) -> io::Result<Child> {
+        try!(options.check());
+        let slave = match self.slave {
+            Some(ref s) => try!(s.try_clone()),
+            None => try!(self.reopen_slave()),
+        };
+        self.slave_shared = true;
+        self.spawn_on(cmd, options, slave)
     }
-}
 
-impl Drop for TtyClient {
-    /// Cleanup the peer TTY
-    fn drop(&mut self) {
-        self.do_flush.store(true, Relaxed);
-        let _ = tcsetattr(self.peer.as_raw_fd(), termios::TCSAFLUSH, &self.termios_orig);
+    fn reopen_slave(&self) -> io::Result<File> {
+        match self.open_slave() {
+            Ok(s) => Ok(s),
+            Err(e) => Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("No TTY slave: {}", e))),
+        }
+    }
 
-        // Restore the append flag if needed
-        let tty_fd = [(&self.peer, self.peer_status), (&self.master, self.master_status)];
-        for &(fd, status) in tty_fd.iter() {
-            if let Some(s) = status {
-                let _ = set_flags(fd.as_raw_fd(), s);
+    // Spawn `cmd` with `slave` as its standard streams
+    fn spawn_on(&mut self, mut cmd: Command, options: SpawnOptions, slave: File) -> io::Result<Child> {
+        if options.export_size {
+            let ws = try!(get_winsize(&self.master));
+            cmd.env("COLUMNS", ws.cols().to_string()).env("LINES", ws.rows().to_string());
+        }
+        // The TERM explicitly set on the command wins
+        if !cmd.get_envs().any(|(k, _)| k == "TERM") {
+            let term = match options.term {
+                Some(t) => Some(t),
+                None => match DEFAULT_TERM.lock() {
+                    Ok(t) => t.clone(),
+                    Err(e) => e.into_inner().clone(),
+                },
+            };
+            if let Some(term) = term {
+                cmd.env("TERM", term);
             }
         }
+        // Each stream owns its slave file descriptor, closed in the parent once spawned
+        if options.close_stdin {
+            cmd.stdin(Stdio::null());
+        } else {
+            cmd.stdin(try!(slave.try_clone()));
+        }
+        match options.stderr {
+            Some(stderr) => {
+                cmd.stdout(slave).stderr(stderr);
+            },
+            None => {
+                cmd.stdout(try!(slave.try_clone())).stderr(slave);
+            },
+        }
+        // SAFETY: The following closures run after the fork, in a copy of this multi-threaded
+        // process: setsid(2) and map_fds() only do async-signal-safe system calls without
+        // allocating, and the user hooks must follow the same rules (cf. `before_exec()`).
+        // Force new session
+        // TODO: tcsetpgrp
+        if options.setsid {
+            // Don't check the error of setsid because it fails if we're the
+            // process leader already. We just forked so it shouldn't return
+            // error, but ignore it anyway.
+            unsafe { cmd.pre_exec(|| { let _ = libc::setsid(); Ok(()) }); }
+        }
+        if !options.fds.is_empty() {
+            let fds = options.fds;
+            // Nothing can be allocated after the fork
+            let mut tmp = vec![-1; fds.len()];
+            let min_fd = fds.iter().map(|&(_, dst)| dst).max().unwrap_or(0) + 1;
+            unsafe { cmd.pre_exec(move || map_fds(&fds, min_fd, &mut tmp)); }
+        }
+        for hook in options.hooks {
+            unsafe { cmd.pre_exec(hook); }
+        }
+        let child = cmd.spawn();
+        if let (Ok(child), Some(reaper)) = (&child, &self.reaper) {
+            if let Ok(mut state) = reaper.state.lock() {
+                state.pid = Some(child.id() as pid_t);
+                state.status = None;
+            }
+        }
+        child
+    }
+
+    /// Spawn a new process with the slave TTY as its controlling terminal, then close the master
+    ///
+    /// This lets a launcher start a process on a TTY and exit without holding its session.
+    /// `Command::spawn()` only returns once the process executed (synchronized with a
+    /// close-on-exec pipe), hence after it acquired its controlling terminal. As with any closed
+    /// terminal, the process then gets a SIGHUP and its TTY reads and writes fail: it must
+    /// handle them, e.g. as a daemon ignoring SIGHUP and reopening its standard streams.
+    pub fn spawn_detached(mut self, cmd: Command) -> io::Result<Child> {
+        let options = SpawnOptions::new().before_exec(Box::new(|| {
+            // The slave is the standard input, and the process is a session leader
+            match unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }));
+        self.spawn_with(cmd, options)
+    }
+
+    /// Get the foreground process group of the TTY (cf. `tcgetpgrp(3)`)
+    pub fn foreground_pgrp(&self) -> io::Result<pid_t> {
+        let mut pgrp: pid_t = 0;
+        match unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCGPGRP, &mut pgrp) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(pgrp),
+        }
+    }
+
+    /// Wait until the foreground process group is not `from` anymore (e.g. a shell started a
+    /// command), and return the new one
+    ///
+    /// A `TimedOut` error is returned if it didn't change before `timeout`.
+    pub fn wait_foreground_change(&self, from: pid_t, timeout: Duration) -> io::Result<pid_t> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let pgrp = try!(self.foreground_pgrp());
+            if pgrp != from {
+                return Ok(pgrp);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Foreground process group unchanged"));
+            }
+            // There is no notification for this change
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(FOREGROUND_POLL_MS)));
+        }
+    }
+
+    /// Automatically reap the process spawned with `spawn()` when it exits
+    ///
+    /// The sigchld_handler must handle the SIGCHLD signal. This handler can be created with
+    /// `chan_signal::notify(&[Signal::CHLD])` from the chan_signal crate.
+    ///
+    /// Only the spawned process is waited for (i.e. `waitpid(2)` on its PID), which leaves the
+    /// other children to their owners. However, the `Child` returned by `spawn()` must not be
+    /// waited for anymore: its exit status is only available through `child_status()`.
+    ///
+    /// Any and all threads spawned must come after the first call to chan_signal::notify!
+    pub fn enable_auto_reap(&mut self, sigchld_handler: chan::Receiver<Signal>) {
+        let state_main = Arc::new(Mutex::new(ReapState {
+            pid: None,
+            status: None,
+        }));
+        let (stop_tx, stop_rx) = chan::sync(0);
+        let state = state_main.clone();
+        thread::spawn(move || {
+            'select: loop {
+                chan_select! {
+                    sigchld_handler.recv() -> signal => {
+                        match signal {
+                            Some(Signal::CHLD) => {},
+                            Some(_) => continue 'select,
+                            // No more signal will come once all the senders are dropped
+                            None => break,
+                        }
+                        if let Ok(mut state) = state.lock() {
+                            reap_child(&mut state);
+                        }
+                    },
+                    stop_rx.recv() => {
+                        break;
+                    }
+                }
+            }
+        });
+        self.reaper = Some(Reaper {
+            state: state_main,
+            _stop: stop_tx,
+        });
+    }
+
+    /// Get the exit status of the spawned process if it was reaped
+    ///
+    /// Always return `None` if `enable_auto_reap()` was not called before `spawn()`.
+    pub fn child_status(&self) -> Option<ExitStatus> {
+        match self.reaper {
+            Some(ref reaper) => match reaper.state.lock() {
+                Ok(mut state) => {
+                    // The SIGCHLD may have been received before the PID was known
+                    reap_child(&mut state);
+                    state.status
+                },
+                Err(_) => None,
+            },
+            None => None,
+        }
+    }
+}
+
+// Only wait for our own child to not steal the exit status of others
+fn reap_child(state: &mut ReapState) {
+    if state.status.is_some() {
+        return;
+    }
+    if let Some(pid) = state.pid {
+        let mut status: c_int = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+            state.status = Some(ExitStatus::from_raw(status));
+        }
+    }
+}
+
+impl AsRef<Path> for TtyServer {
+    /// Get the server TTY path
+    fn as_ref(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+/// Run `cmd` in a new TTY bound to the current terminal (i.e. stdin and stdout)
+///
+/// The TTY is configured according to stdin, the client is bound before spawning the command to
+/// not miss any output, and the terminal resizing is handled with a chan_signal sigwinch_handler:
+/// this must then be called before any and all other threads are spawned. The current stdin and
+/// stdout stay open once the client is dropped.
+pub fn attach_current(cmd: Command) -> io::Result<(TtyServer, TtyClient, Child)> {
+    let signal = chan_signal::notify(&[Signal::WINCH]);
+    let stdin = FileDesc::new(libc::STDIN_FILENO, false);
+    attach_fds(cmd, Some(&stdin), libc::STDIN_FILENO, libc::STDOUT_FILENO, Some(signal))
+}
+
+// Same as `attach_current()` but bound to `peer_in` and `peer_out`, which are duplicated
+fn attach_fds<T>(cmd: Command, template: Option<&T>, peer_in: RawFd, peer_out: RawFd,
+                 sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+        io::Result<(TtyServer, TtyClient, Child)> where T: AsRawFd {
+    let mut server = try!(TtyServer::new(template));
+    let peer_in = try!(dup_cloexec(peer_in));
+    let peer_out = try!(dup_cloexec(peer_out));
+    let master = try!(dup_cloexec(server.master.as_raw_fd()));
+    let mut client = try!(TtyClientBuilder::new().build_split(master, peer_in, peer_out, sigwinch_handler));
+    client._server_ref = Some(server.clients.clone());
+    let child = try!(server.spawn(cmd));
+    Ok((server, client, child))
+}
+
+/// Set the `TERM` environment variable of all the processes spawned from now on
+///
+/// This can be overridden for a process with `SpawnOptions::term()` or by setting `TERM` on its
+/// `Command`. Without default, the processes inherit the `TERM` of the current process.
+pub fn set_default_term(term: &str) {
+    let mut default = match DEFAULT_TERM.lock() {
+        Ok(t) => t,
+        Err(e) => e.into_inner(),
+    };
+    *default = Some(term.to_string());
+}
+
+/// Wait until the binding of one of the `clients` broke, and return its index
+///
+/// This is the same as `TtyClient::wait()` for multiple clients.
+///
+/// # Panics
+///
+/// Panics if `clients` is empty, which would wait forever.
+pub fn wait_any(clients: &[&TtyClient]) -> usize {
+    assert!(!clients.is_empty(), "No client to wait for");
+    loop {
+        if let Some(i) = clients.iter().position(|c| c.try_wait()) {
+            return i;
+        }
+        // A paused client has no proxy to stop but its master can still hang up
+        let mut pfds: Vec<libc::pollfd> = clients.iter().map(|c| libc::pollfd {
+            fd: if c.paused { c.master.as_raw_fd() } else { c.proxy.flush_request_fd() },
+            events: if c.paused { 0 } else { libc::POLLIN },
+            revents: 0,
+        }).collect();
+        let _ = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, -1) };
+    }
+}
+
+/// Run a new command in a new TTY for each connection accepted by `listener`
+///
+/// The command is created by `cmd_factory` and its TTY is bound to the socket, which is not a
+/// TTY: the remote end is in charge of its own terminal configuration, and the TTY keeps its
+/// default size. Each connection is handled by a dedicated thread until the process or the
+/// socket is closed, and its failure, if any, is written to the standard error. This only
+/// returns on `accept(2)` error.
+pub fn serve_tcp<F>(listener: &TcpListener, cmd_factory: F) -> io::Result<()> where F: Fn() -> Command {
+    serve_tcp_with(listener, cmd_factory, TcpResize::Fixed, |e| {
+        eprintln!("Failed to serve a TTY connection: {}", e);
+    })
+}
+
+/// Same as `serve_tcp()` but handle the TTY size according to `resize`, and give the failure of
+/// a connection (e.g. the command can't be spawned) to `on_error`
+pub fn serve_tcp_with<F, E>(listener: &TcpListener, cmd_factory: F, resize: TcpResize, on_error: E) ->
+        io::Result<()> where F: Fn() -> Command, E: Fn(io::Error) + Send + Sync + 'static {
+    let on_error = Arc::new(on_error);
+    for stream in listener.incoming() {
+        let stream = try!(stream);
+        let cmd = cmd_factory();
+        let on_error = on_error.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, cmd, resize) {
+                on_error(e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, cmd: Command, resize: TcpResize) -> io::Result<()> {
+    try!(stream.set_nodelay(true));
+    let mut server = try!(TtyServer::new_default());
+    let (client, filter) = match resize {
+        TcpResize::Fixed => (try!(server.new_client(stream, None)), None),
+        TcpResize::Control => {
+            let pipe = try!(new_pipe(None));
+            let input = try!(stream.try_clone());
+            let shutdown = try!(stream.try_clone());
+            let master = try!(dup_cloexec(server.master.as_raw_fd()));
+            let mut client = try!(TtyClientBuilder::new().build_split(master, pipe.reader, stream, None));
+            client._server_ref = Some(server.clients.clone());
+            let filter = spawn_control_filter(input, pipe.writer, try!(server.master.try_clone()));
+            (client, Some((shutdown, filter)))
+        },
+    };
+    let mut child = try!(server.spawn(cmd));
+    client.wait();
+    if let Some((input, filter)) = filter {
+        // Unblock the filter if the process exited first
+        let _ = input.shutdown(Shutdown::Read);
+        let _ = filter.join();
+    }
+    // Hang up the TTY if the connection was closed first
+    drop(client);
+    drop(server);
+    try!(child.wait());
+    Ok(())
+}
+
+// Forward the socket input to the peer input pipe, without the control messages applied to
+// the master
+fn spawn_control_filter(mut stream: TcpStream, mut peer_in: File, master: File) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut parser = ControlParser::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let (data, msgs) = parser.feed(&buf[..n]);
+            for msg in msgs {
+                match msg {
+                    ControlMsg::Resize(ws) => {
+                        let _ = set_winsize(&master, &ws);
+                    },
+                }
+            }
+            if peer_in.write_all(&data).is_err() {
+                return;
+            }
+        }
+        let _ = peer_in.write_all(&parser.finish());
+        // The peer input pipe is closed when dropped, which stops the client
+    })
+}
+
+// Write the input back to the peer, unless the slave already echoes it
+fn echo_hook(master: RawFd, peer_out: RawFd, crlf: bool) -> RawInputHook {
+    Box::new(move |data: &[u8]| {
+        match Termios::from_fd(master) {
+            Ok(ref t) if t.c_lflag & termios::ECHO == 0 => {},
+            _ => return,
+        }
+        let _ = if crlf && data.contains(&b'\r') {
+            let mut echo = Vec::with_capacity(data.len() * 2);
+            for &c in data {
+                echo.push(c);
+                if c == b'\r' {
+                    echo.push(b'\n');
+                }
+            }
+            write_all_fd(peer_out, &echo)
+        } else {
+            write_all_fd(peer_out, data)
+        };
+    })
+}
+
+// Write everything to a possibly nonblocking file descriptor
+fn write_all_fd(fd: RawFd, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match unsafe { libc::write(fd, buf.as_ptr() as *const _, buf.len()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => {},
+                    io::ErrorKind::WouldBlock => {
+                        poll_one(fd, libc::POLLOUT, -1);
+                    },
+                    _ => return Err(err),
+                }
+            },
+            n => buf = &buf[n as usize..],
+        }
+    }
+    Ok(())
+}
+
+// Find a DA1 answer (i.e. "\e[?Ps;...;Psc") and return its bounds and parameters
+fn parse_da1(buf: &[u8]) -> Option<(usize, usize, Vec<u32>)> {
+    let mut start = 0;
+    while let Some(pos) = buf[start..].windows(3).position(|w| w == b"\x1b[?") {
+        let begin = start + pos;
+        let mut params = vec![0u32];
+        for (i, &c) in buf[begin + 3..].iter().enumerate() {
+            match c {
+                b'0'..=b'9' => {
+                    let last = params.len() - 1;
+                    params[last] = params[last].saturating_mul(10).saturating_add((c - b'0') as u32);
+                },
+                b';' => params.push(0),
+                b'c' => return Some((begin, begin + 3 + i + 1, params)),
+                _ => break,
+            }
+        }
+        start = begin + 1;
+    }
+    None
+}
+
+fn query_da1(peer_in: RawFd, peer_out: RawFd, master: RawFd, timeout: Duration) -> io::Result<Vec<u32>> {
+    try!(write_all_fd(peer_out, b"\x1b[c"));
+    let deadline = Instant::now() + timeout;
+    let mut input = Vec::new();
+    let mut buf = [0u8; 256];
+    let found = loop {
+        if let Some(found) = parse_da1(&input) {
+            break Some(found);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            break None;
+        }
+        let remaining = deadline - now;
+        // Round up to not spin during the last millisecond
+        let ms = remaining.as_secs() * 1000 + (remaining.subsec_nanos() as u64 + 999_999) / 1_000_000;
+        if !poll_one(peer_in, libc::POLLIN, cmp::min(ms, c_int::MAX as u64) as c_int) {
+            continue;
+        }
+        match unsafe { libc::read(peer_in, buf.as_mut_ptr() as *mut _, buf.len()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => {},
+                    _ => return Err(err),
+                }
+            },
+            0 => break None,
+            n => input.extend_from_slice(&buf[..n as usize]),
+        }
+    };
+    // Do not lose the user input
+    match found {
+        Some((begin, end, params)) => {
+            input.drain(begin..end);
+            try!(write_all_fd(master, &input));
+            Ok(params)
+        },
+        None => {
+            try!(write_all_fd(master, &input));
+            Err(io::Error::new(io::ErrorKind::TimedOut, "No DA1 answer from the peer"))
+        },
+    }
+}
+
+// Replace a pipe write end with /dev/null, which notifies the reader without releasing the file
+// descriptor number still owned by its FileDesc
+fn close_pipe_writer(fd: RawFd) -> io::Result<()> {
+    let file = unsafe { File::from_raw_fd(fd) };
+    let is_fifo = file.metadata().map(|m| m.file_type().is_fifo());
+    let _ = file.into_raw_fd();
+    if !try!(is_fifo) {
+        return Ok(());
+    }
+    let null = try!(OpenOptions::new().write(true).open("/dev/null"));
+    match unsafe { libc::dup3(null.as_raw_fd(), fd, libc::O_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn dup_cloexec(fd: RawFd) -> io::Result<FileDesc> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(FileDesc::new(fd, true)),
+    }
+}
+
+fn is_hangup<T>(fd: &T) -> bool where T: AsRawFd {
+    let mut pfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) == 1 && pfd.revents & libc::POLLHUP != 0 }
+}
+
+// Wait until `fd` hangs up, without consuming nor waiting for its data
+fn wait_hangup<T>(fd: &T) where T: AsRawFd {
+    let mut pfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: 0,
+        revents: 0,
+    };
+    while unsafe { libc::poll(&mut pfd, 1, -1) } == -1 &&
+            io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {}
+}
+
+fn is_tty(fd: RawFd) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+fn get_flags(fd: RawFd) -> io::Result<c_int> {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        -1 => Err(io::Error::last_os_error()),
+        flags => Ok(flags),
+    }
+}
+
+// Only set the append flag back, which keeps the other flags changed in the meantime through a
+// shared open file description (e.g. a dup'd file descriptor)
+fn restore_append_flag(fd: RawFd) -> io::Result<()> {
+    let flags = try!(get_flags(fd));
+    set_flags(fd, flags | libc::O_APPEND)
+}
+
+// Called after the fork: must be async-signal-safe
+fn map_fds(fds: &[(RawFd, RawFd)], min_fd: RawFd, tmp: &mut [RawFd]) -> io::Result<()> {
+    // Move all the sources out of the way first in case one of them is also a destination
+    for (i, &(src, _)) in fds.iter().enumerate() {
+        tmp[i] = match unsafe { libc::fcntl(src, libc::F_DUPFD_CLOEXEC, min_fd) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => fd,
+        };
+    }
+    // The temporary copies are closed by the exec whereas the destinations are inherited
+    for (i, &(_, dst)) in fds.iter().enumerate() {
+        if unsafe { libc::dup2(tmp[i], dst) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Copy the peer size to the master for each resize event, or once a burst of them ended
+struct Resizer {
+    master: FileDesc,
+    peer: FileDesc,
+    on_resize: Option<Arc<Mutex<ResizeHook>>>,
+    debounce: Option<Duration>,
+    // End of the quiet period of a pending resize
+    due: Option<Instant>,
+    // Single timer at a time, which may fire before the end of the quiet period
+    deadline: Option<chan::Receiver<()>>,
+    // Never receives anything
+    idle: (chan::Sender<()>, chan::Receiver<()>),
+}
+
+impl Resizer {
+    fn new(master: RawFd, peer: RawFd, on_resize: Option<Arc<Mutex<ResizeHook>>>,
+           debounce: Option<Duration>) -> Resizer {
+        Resizer {
+            // master and peer FD will be close by TtyClient::drop()
+            master: FileDesc::new(master, false),
+            peer: FileDesc::new(peer, false),
+            on_resize,
+            debounce,
+            due: None,
+            deadline: None,
+            idle: chan::sync(0),
+        }
+    }
+
+    fn request(&mut self) {
+        match self.debounce {
+            None => copy_winsize(&self.peer, &self.master, &self.on_resize),
+            // Restart the quiet period, without a new timer for each event of a burst
+            Some(quiet) => {
+                self.due = Some(Instant::now() + quiet);
+                if self.deadline.is_none() {
+                    self.deadline = Some(chan::after(quiet));
+                }
+            },
+        }
+    }
+
+    // Apply the pending resize once the quiet period ended, or wait for the rest of it
+    fn timeout(&mut self) {
+        let now = Instant::now();
+        match self.due {
+            Some(due) if due > now => self.deadline = Some(chan::after(due - now)),
+            _ => self.flush(),
+        }
+    }
+
+    fn deadline(&self) -> chan::Receiver<()> {
+        match self.deadline {
+            Some(ref d) => d.clone(),
+            None => self.idle.1.clone(),
+        }
+    }
+
+    // Apply the pending resize, if any
+    fn flush(&mut self) {
+        self.deadline = None;
+        if self.due.take().is_some() {
+            copy_winsize(&self.peer, &self.master, &self.on_resize);
+        }
+    }
+}
+
+// Ignore errors and notify the hook, if any
+fn copy_winsize<T, U>(src: &T, dst: &U, hook: &Option<Arc<Mutex<ResizeHook>>>) where T: AsRawFd, U: AsRawFd {
+    if let Ok(ws) = get_winsize(src) {
+        let _ = set_winsize(dst, &ws);
+        if let Some(ref hook) = *hook {
+            if let Ok(mut hook) = hook.lock() {
+                (*hook)(ws);
+            }
+        }
+    }
+}
+
+// Read the master until all the slave file descriptors are closed
+struct MasterReader(File);
+
+impl Read for MasterReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // The master returns EIO once all the slave file descriptors are closed
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            r => r,
+        }
+    }
+}
+
+impl<'a> Iterator for OutputChunks<'a> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut buf = [0u8; 4096];
+        while !self.done {
+            match self.master.read(&mut buf) {
+                Ok(0) => self.done = true,
+                Ok(n) => return Some(Ok(buf[..n].to_vec())),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // The master returns EIO once all the slave file descriptors are closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        None
+    }
+}
+
+impl TtyClientBuilder {
+    /// Create a configuration matching `TtyClient::new()`
+    pub fn new() -> TtyClientBuilder {
+        TtyClientBuilder {
+            config: BindConfig {
+                flow_control: FlowControl::Peer,
+                append_flag: AppendFlagPolicy::Lenient,
+                input: true,
+                output: true,
+                restore_action: SetAction::Flush,
+                pipe_size: None,
+                local_echo: false,
+                local_echo_crlf: false,
+                watchdog: None,
+                reset_display: false,
+                resize_debounce: None,
+                translate_cr: false,
+            },
+            output_recorder: None,
+            buffer_pool: None,
+            resize_trigger: None,
+            on_resize: None,
+            on_input: None,
+            other_signals: None,
+            session_meta: None,
+            keep_master_flags: false,
+            restore_winsize: false,
+            max_duration: None,
+        }
+    }
+
+    /// Choose which side handles the software flow control (i.e. IXON and IXOFF)
+    pub fn flow_control(mut self, flow_control: FlowControl) -> TtyClientBuilder {
+        self.config.flow_control = flow_control;
+        self
+    }
+
+    /// Choose how to handle a failure to unset the append flag of the master or the peer
+    pub fn append_flag_policy(mut self, policy: AppendFlagPolicy) -> TtyClientBuilder {
+        self.config.append_flag = policy;
+        self
+    }
+
+    /// Never change the file status flags of the master (disabled by default)
+    ///
+    /// The master may be shared with a `TtyServer` or another client. Without the append flag
+    /// unset, the input can't be forwarded to a master opened with this flag.
+    pub fn keep_master_flags(mut self, keep: bool) -> TtyClientBuilder {
+        self.keep_master_flags = keep;
+        self
+    }
+
+    /// Restore the master window size when the client is dropped (disabled by default)
+    ///
+    /// The window size is otherwise left as set by the last resize according to the peer.
+    pub fn restore_winsize(mut self, restore: bool) -> TtyClientBuilder {
+        self.restore_winsize = restore;
+        self
+    }
+
+    /// Translate a carriage return typed on the peer to a new line (disabled by default)
+    ///
+    /// The peer input is otherwise forwarded as is, and the slave TTY configuration decides
+    /// (`ICRNL` is set by default). This helps processes expecting a new line when the slave is
+    /// in raw mode (e.g. `TtyServer::new_raw()`). This is only relevant for a peer TTY.
+    pub fn translate_cr(mut self, translate: bool) -> TtyClientBuilder {
+        self.config.translate_cr = translate;
+        self
+    }
+
+    /// Reset the peer display when the client is dropped (disabled by default)
+    ///
+    /// This is done before restoring the peer TTY configuration (cf. `TtyClient::reset_peer_display()`).
+    pub fn reset_display_on_drop(mut self, reset: bool) -> TtyClientBuilder {
+        self.config.reset_display = reset;
+        self
+    }
+
+    /// Choose how the peer TTY configuration is restored (`SetAction::Flush` by default)
+    ///
+    /// With `SetAction::Drain`, the input typed ahead during the teardown is kept for the next
+    /// reader of the peer.
+    pub fn restore_action(mut self, action: SetAction) -> TtyClientBuilder {
+        self.config.restore_action = action;
+        self
+    }
+
+    /// Set the capacity of the pipes used to forward the data, up to the system limit
+    ///
+    /// A bigger capacity than the default (i.e. 64 KiB) may improve the throughput of high
+    /// output rates.
+    pub fn pipe_size(mut self, bytes: usize) -> TtyClientBuilder {
+        self.config.pipe_size = Some(bytes);
+        self
+    }
+
+    /// Forward the data with `read(2)` and `write(2)` through buffers taken from `pool` (disabled
+    /// by default)
+    ///
+    /// Each forwarded direction uses one buffer, given back to the pool once the proxy stops,
+    /// instead of the kernel pipes of `splice(2)`. The pipe size then only applies to the hooks
+    /// and the recorder.
+    pub fn buffer_pool(mut self, pool: BufferPool) -> TtyClientBuilder {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Choose which directions are forwarded (both by default)
+    ///
+    /// The `input` goes from the peer to the master (e.g. a remote keyboard) and the `output`
+    /// from the master to the peer (e.g. a viewer). At least one of them must be enabled.
+    pub fn directions(mut self, input: bool, output: bool) -> TtyClientBuilder {
+        self.config.input = input;
+        self.config.output = output;
+        self
+    }
+
+    /// Record all the output forwarded to the peer into `writer`
+    pub fn record_output<W>(mut self, writer: W) -> TtyClientBuilder where W: Write + Send + 'static {
+        self.output_recorder = Some(Recorder::new(Box::new(writer), None));
+        self
+    }
+
+    /// Same as `record_output()` but only record up to `max_bytes` according to `on_limit`
+    ///
+    /// With `OnLimit::Truncate`, the last output is kept in memory and only written to `writer`
+    /// when the client is dropped.
+    pub fn record_output_limited<W>(mut self, writer: W, max_bytes: usize, on_limit: OnLimit) ->
+            TtyClientBuilder where W: Write + Send + 'static {
+        self.output_recorder = Some(Recorder::new(Box::new(writer), Some((max_bytes, on_limit))));
+        self
+    }
+
+    /// Same as `record_output()` but compress the recording with `codec`
+    ///
+    /// The compression stream is finalized when the client is dropped.
+    #[cfg(any(feature = "flate2", feature = "zstd"))]
+    pub fn record_output_compressed<W>(mut self, writer: W, codec: Codec) -> io::Result<TtyClientBuilder>
+            where W: Write + Send + 'static {
+        self.output_recorder = Some(try!(Recorder::compressed(Box::new(writer), codec, None)));
+        Ok(self)
+    }
+
+    /// Same as `record_output()` but create or truncate the file at `path`
+    ///
+    /// The file is opened right away to report an invalid path before binding the client.
+    pub fn record_output_to_path<P>(self, path: P) -> io::Result<TtyClientBuilder> where P: AsRef<Path> {
+        let file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(path));
+        Ok(self.record_output(file))
+    }
+
+    /// Stop the proxy once the client has been bound for `duration`, whatever the activity
+    ///
+    /// The deadline is not extended by `TtyClient::rebind_peer()`. If `hangup` is true, SIGHUP is
+    /// first sent to the foreground process group of the terminal. `TtyClient::wait()` then
+    /// returns and `TtyClient::expired()` is true.
+    pub fn max_duration(mut self, duration: Duration, hangup: bool) -> TtyClientBuilder {
+        self.max_duration = Some((duration, hangup));
+        self
+    }
+
+    /// Stop the proxy if it is stuck for `interval`, i.e. nothing was forwarded whereas some data
+    /// is waiting to be (e.g. a peer which never reads its output)
+    ///
+    /// The proxy then stops with a `TimedOut` error (cf. `TtyClient::take_error()`), without
+    /// forwarding the remaining data. A write already blocked on the peer can't be interrupted,
+    /// which may still delay the client drop.
+    pub fn watchdog(mut self, interval: Duration) -> TtyClientBuilder {
+        self.config.watchdog = Some(interval);
+        self
+    }
+
+    /// Send to `handler` the signals other than SIGWINCH received from the sigwinch_handler
+    ///
+    /// These signals are otherwise dropped, which only matters if the sigwinch_handler was
+    /// created for other signals too. The handler should not block the resizing, e.g. with a
+    /// `chan::async()` channel.
+    pub fn other_signals(mut self, handler: chan::Sender<Signal>) -> TtyClientBuilder {
+        self.other_signals = Some(handler);
+        self
+    }
+
+    /// Write the forwarded input back to the peer (disabled by default)
+    ///
+    /// This is useful when the peer doesn't echo its input itself (e.g. a remote terminal). The
+    /// input is not echoed while the slave TTY already does it (i.e. ECHO is set), to not echo it
+    /// twice. The input is echoed as read, including the escape sequences (e.g. the bracketed
+    /// paste boundaries).
+    pub fn local_echo(mut self, echo: bool) -> TtyClientBuilder {
+        self.config.local_echo = echo;
+        self
+    }
+
+    /// Echo a carriage return as a new line, i.e. CRLF (disabled by default)
+    ///
+    /// This is only relevant with `local_echo()`.
+    pub fn local_echo_crlf(mut self, crlf: bool) -> TtyClientBuilder {
+        self.config.local_echo_crlf = crlf;
+        self
+    }
+
+    /// Write `meta` as a JSON line at the beginning of the output recording, if any
+    ///
+    /// Without explicit size, the terminal size is taken from the master when binding the client.
+    pub fn record_metadata(mut self, meta: SessionMeta) -> TtyClientBuilder {
+        self.session_meta = Some(meta);
+        self
+    }
+
+    /// Update the terminal window size according to the peer each time `trigger` receives an event
+    ///
+    /// This is an alternative to the sigwinch_handler for applications not using chan_signal:
+    /// their own SIGWINCH handling only needs to send `()` to the other end of the channel.
+    /// The resizing stops when this sender is dropped.
+    pub fn resize_trigger(mut self, trigger: chan::Receiver<()>) -> TtyClientBuilder {
+        self.resize_trigger = Some(trigger);
+        self
+    }
+
+    /// Coalesce the bursts of resize events (e.g. while dragging a window border)
+    ///
+    /// The peer size is only applied once no other resize event came during `quiet`, which
+    /// avoids to redraw the application at each step. The last size is always applied.
+    pub fn resize_debounce(mut self, quiet: Duration) -> TtyClientBuilder {
+        self.config.resize_debounce = Some(quiet);
+        self
+    }
+
+    /// Call `hook` with the new peer window size each time the terminal is resized
+    ///
+    /// This is called from the sigwinch_handler or the `resize_trigger()` thread, after the
+    /// master window size is updated (e.g. to notify a remote terminal).
+    pub fn on_resize(mut self, hook: Box<FnMut(WinSize) + Send>) -> TtyClientBuilder {
+        self.on_resize = Some(hook);
+        self
+    }
+
+    /// Call `hook` with the input forwarded to the master, and whether it is pasted content
+    ///
+    /// The pasted content is delimited by the bracketed paste sequences, which are not given to
+    /// the hook but still forwarded. This only happens when the application enabled the
+    /// bracketed paste mode of the peer terminal. A UTF-8 character split across two reads is
+    /// given whole with the next chunk. The hook is called from a dedicated thread, but
+    /// the forwarding waits for it if it lags behind by more than a pipe capacity.
+    pub fn on_input(mut self, hook: InputHook) -> TtyClientBuilder {
+        self.on_input = Some(hook);
+        self
+    }
+
+    /// Setup the peer TTY client (e.g. stdio) and bind it to the master TTY server
+    ///
+    /// See `TtyClient::new()` for the sigwinch_handler requirements.
+    pub fn build<T, U>(self, master: T, peer: U, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
+        let master = FileDesc::new(master.into_raw_fd(), true);
+        let peer = FileDesc::new(peer.into_raw_fd(), true);
+        self.bind(master, peer, None, sigwinch_handler)
+    }
+
+    /// Same as `build()` but read the peer input from `peer_in` and write its output to `peer_out`
+    ///
+    /// The termios configuration is only applied to the first of them which is a TTY, if any.
+    pub fn build_split<T, U, V>(self, master: T, peer_in: U, peer_out: V,
+                                sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd, V: AsRawFd + IntoRawFd {
+        let master = FileDesc::new(master.into_raw_fd(), true);
+        let peer_in = FileDesc::new(peer_in.into_raw_fd(), true);
+        let peer_out = FileDesc::new(peer_out.into_raw_fd(), true);
+        self.bind(master, peer_in, Some(peer_out), sigwinch_handler)
+    }
+
+    fn bind(self, master: FileDesc, peer: FileDesc, peer_out: Option<FileDesc>,
+            sigwinch_handler: Option<chan::Receiver<Signal>>) -> io::Result<TtyClient> {
+        let config = self.config;
+        if !config.input && !config.output {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No direction to forward"));
+        }
+        if self.buffer_pool.as_ref().map_or(false, |p| p.buffer_size() == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty proxy buffers"));
+        }
+
+        // The slave TTY configuration is reachable through the master
+        if config.flow_control != FlowControl::Peer {
+            let mut termios_slave = try!(Termios::from_fd(master.as_raw_fd()));
+            match config.flow_control {
+                FlowControl::Slave => termios_slave.c_iflag |= termios::IXON | termios::IXOFF,
+                _ => termios_slave.c_iflag &= !(termios::IXON | termios::IXOFF),
+            }
+            try!(tcsetattr(master.as_raw_fd(), termios::TCSANOW, &termios_slave));
+        }
+        // Only restored if changed
+        let master_status = if config.input && !self.keep_master_flags {
+            try!(config.unset_append_flag(master.as_raw_fd()))
+        } else {
+            None
+        };
+
+        let winsize_orig = if self.restore_winsize {
+            Some(try!(get_winsize(&master)))
+        } else {
+            None
+        };
+
+        let id = SessionId::next();
+        let mut output_recorder = self.output_recorder;
+        if let (Some(recorder), Some(meta)) = (output_recorder.as_mut(), self.session_meta) {
+            recorder.header(meta, try!(get_winsize(&master)), id);
+        }
+
+        let peer_tty = find_peer_tty(&peer, &peer_out);
+        let (termios_orig, peer_status) = try!(config.setup_peer(peer_tty, &peer, &peer_out));
+        let (event_tx, event_rx) = channel();
+        let (stop_tx, _) = chan::sync(0);
+        let mut client = TtyClient {
+            peer,
+            peer_out,
+            peer_status,
+            master,
+            master_status,
+            winsize_orig,
+            peer_tty,
+            termios_orig,
+            config,
+            sigwinch_handler,
+            resize_trigger: self.resize_trigger,
+            on_resize: self.on_resize.map(|h| Arc::new(Mutex::new(h))),
+            on_input: self.on_input.map(|h| Arc::new(Mutex::new(h))),
+            other_signals: self.other_signals,
+            #[cfg(feature = "signal-hook")]
+            signal_hook: None,
+            proxy: try!(Proxy::new(event_tx)),
+            flush_event: event_rx,
+            child_gone: Arc::new(AtomicBool::new(false)),
+            max_duration: self.max_duration.map(|(d, hangup)| (Instant::now() + d, hangup)),
+            expired: Arc::new(AtomicBool::new(false)),
+            proxy_threads: Vec::new(),
+            paused: false,
+            output_recorder: output_recorder.map(|r| Arc::new(Mutex::new(r))),
+            buffer_pool: self.buffer_pool,
+            _stop: stop_tx,
+            _server_ref: None,
+            id,
+        };
+        // The peer configuration is restored by TtyClient::drop() on error
+        try!(client.spawn_proxy());
+        Ok(client)
+    }
+}
+
+impl Default for TtyClientBuilder {
+    fn default() -> TtyClientBuilder {
+        TtyClientBuilder::new()
+    }
+}
+
+impl Default for SpawnOptions {
+    fn default() -> SpawnOptions {
+        SpawnOptions::new()
+    }
+}
+
+impl SpawnOptions {
+    /// Only pass the slave TTY as the standard streams, in a new session
+    pub fn new() -> SpawnOptions {
+        SpawnOptions {
+            fds: Vec::new(),
+            setsid: true,
+            close_stdin: false,
+            stderr: None,
+            export_size: false,
+            term: None,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Choose whether the process is started in a new session (default) or in the current one
+    ///
+    /// Without a new session, the slave can't become the controlling terminal of the process,
+    /// which keeps the one of the parent, if any. The line discipline signals (e.g. SIGINT on
+    /// ^C) are then sent to the foreground process group of this other terminal, and the process
+    /// can be stopped by its job control.
+    pub fn setsid(mut self, setsid: bool) -> SpawnOptions {
+        self.setsid = setsid;
+        self
+    }
+
+    /// Give `/dev/null` as the standard input instead of the slave TTY (disabled by default)
+    ///
+    /// The process then reads an immediate end of file, e.g. for a batch job, while its output
+    /// still goes to the slave. Programs looking for their terminal on the standard input (e.g.
+    /// `tty(1)`) don't find any, but the slave can still become the controlling terminal through
+    /// the standard output.
+    pub fn close_stdin(mut self, close: bool) -> SpawnOptions {
+        self.close_stdin = close;
+        self
+    }
+
+    /// Send the standard error of the process to `stderr` (e.g. a pipe) instead of the slave TTY
+    ///
+    /// The error messages are then not part of the TTY output, nor interleaved with it.
+    pub fn stderr(mut self, stderr: Stdio) -> SpawnOptions {
+        self.stderr = Some(stderr);
+        self
+    }
+
+    /// Pass `fd` to the process with the same number (e.g. a status pipe)
+    pub fn keep_fd(self, fd: RawFd) -> SpawnOptions {
+        self.map_fd(fd, fd)
+    }
+
+    /// Pass `src` to the process as `dst`
+    ///
+    /// Neither `src` nor `dst` can be one of the standard streams, which are the slave TTY in
+    /// the process.
+    pub fn map_fd(mut self, src: RawFd, dst: RawFd) -> SpawnOptions {
+        self.fds.push((src, dst));
+        self
+    }
+
+    /// Set the `COLUMNS` and `LINES` environment variables to the TTY size (disabled by default)
+    ///
+    /// This is useful for programs which don't query the TTY size, but these variables are not
+    /// updated when the TTY is resized.
+    pub fn export_size(mut self, export_size: bool) -> SpawnOptions {
+        self.export_size = export_size;
+        self
+    }
+
+    /// Set the `TERM` environment variable of the process, instead of the `set_default_term()` one
+    pub fn term(mut self, term: &str) -> SpawnOptions {
+        self.term = Some(term.to_string());
+        self
+    }
+
+    /// Run `hook` in the child process just before the exec (e.g. to drop privileges)
+    ///
+    /// The hooks are called in order, after the new session is created and the file descriptors
+    /// are mapped. An error aborts the spawn. As for `CommandExt::pre_exec()`, the hook runs
+    /// after a fork: it must only call async-signal-safe functions and must not allocate nor
+    /// take a lock which may be held by another thread of the parent.
+    ///
+    /// The hook is a boxed trait object written without `dyn`, as the other callbacks of this
+    /// Rust 2015 crate.
+    pub fn before_exec(mut self, hook: Box<FnMut() -> io::Result<()> + Send + Sync>) -> SpawnOptions {
+        self.hooks.push(hook);
+        self
+    }
+
+    fn check(&self) -> io::Result<()> {
+        for (i, &(src, dst)) in self.fds.iter().enumerate() {
+            if src <= libc::STDERR_FILENO || dst <= libc::STDERR_FILENO {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Can't map a standard stream"));
+            }
+            if self.fds[..i].iter().any(|&(_, d)| d == dst) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Duplicate destination file descriptor"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BindConfig {
+    fn unset_append_flag(&self, fd: RawFd) -> io::Result<Option<c_int>> {
+        match unset_append_flag(fd) {
+            // Nothing to restore
+            Err(ref e) if self.append_flag == AppendFlagPolicy::Lenient &&
+                e.raw_os_error() != Some(libc::EBADF) => Ok(None),
+            r => r,
+        }
+    }
+
+    // Return the original termios, if any, and the peer output status to restore
+    fn setup_peer(&self, peer_tty: RawFd, peer: &FileDesc, peer_out: &Option<FileDesc>) ->
+            io::Result<(Option<Termios>, Option<c_int>)> {
+        // Setup peer terminal configuration
+        let termios_orig = if is_tty(peer_tty) {
+            let termios_orig = try!(Termios::from_fd(peer_tty));
+            let mut termios_peer = termios_orig;
+            // Keep the peer line discipline (e.g. ^C) when its input is not forwarded
+            if self.input {
+                termios_peer.c_lflag &= !(termios::ECHO | termios::ICANON | termios::ISIG);
+                termios_peer.c_iflag &= !(termios::IGNBRK | termios::ICRNL);
+                termios_peer.c_iflag |= termios::BRKINT;
+                if self.translate_cr {
+                    termios_peer.c_iflag |= termios::ICRNL;
+                }
+                termios_peer.c_cc[termios::VMIN] = 1;
+                termios_peer.c_cc[termios::VTIME] = 0;
+            }
+            // The peer must not swallow XON/XOFF bytes to forward them
+            if self.flow_control != FlowControl::Peer {
+                termios_peer.c_iflag &= !(termios::IXON | termios::IXOFF);
+            }
+            // XXX: cfmakeraw
+            if let Err(e) = tcsetattr(peer_tty, termios::TCSAFLUSH, &termios_peer) {
+                // Some of the changes may have been applied anyway
+                let _ = tcsetattr(peer_tty, termios::TCSANOW, &termios_orig);
+                return Err(io::Error::new(e.kind(), PeerSetupError { cause: e }));
+            }
+            Some(termios_orig)
+        } else {
+            None
+        };
+
+        let peer_status = if self.output {
+            let peer_out_fd = peer_out.as_ref().unwrap_or(peer).as_raw_fd();
+            match self.unset_append_flag(peer_out_fd) {
+                Ok(s) => s,
+                Err(e) => {
+                    if let Some(ref t) = termios_orig {
+                        let _ = tcsetattr(peer_tty, termios::TCSAFLUSH, t);
+                    }
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+        Ok((termios_orig, peer_status))
+    }
+}
+
+// The peer input is preferred if both are TTYs
+fn find_peer_tty(peer: &FileDesc, peer_out: &Option<FileDesc>) -> RawFd {
+    match *peer_out {
+        Some(ref out) if !is_tty(peer.as_raw_fd()) && is_tty(out.as_raw_fd()) => out.as_raw_fd(),
+        _ => peer.as_raw_fd(),
+    }
+}
+
+// TODO: Handle SIGWINCH to dynamically update WinSize
+// TODO: Replace `spawn` with `scoped` and share variables
+impl TtyClient {
+    /// Setup the peer TTY client (e.g. stdio) and bind it to the master TTY server
+    ///
+    /// The sigwinch_handler must handle the SIGWINCH signal to update the TTY window size.
+    /// This handler can be created with `chan_signal::notify(&[Signal::WINCH])` from the
+    /// chan_signal crate. The other signals it may receive are dropped (cf.
+    /// `TtyClientBuilder::other_signals()`).
+    ///
+    /// Any and all threads spawned must come after the first call to chan_signal::notify!
+    pub fn new<T, U>(master: T, peer: U, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
+        TtyClientBuilder::new().build(master, peer, sigwinch_handler)
+    }
+
+    /// Same as `new()` but read the peer input from `peer_in` and write its output to `peer_out`
+    ///
+    /// This is useful when the peer is not a TTY (e.g. a pair of pipes), or when only one of them
+    /// is a TTY. The termios configuration is then only applied to the first of them which is a
+    /// TTY, if any. A `peer_out` pipe is closed as soon as all the slave file descriptors are
+    /// closed, which lets its reader get the end of file before the client is dropped.
+    pub fn new_split<T, U, V>(master: T, peer_in: U, peer_out: V,
+                              sigwinch_handler: Option<chan::Receiver<Signal>>) ->
+            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd, V: AsRawFd + IntoRawFd {
+        TtyClientBuilder::new().build_split(master, peer_in, peer_out, sigwinch_handler)
+    }
+
+    /// Wait until the TTY binding broke (e.g. the connected process exited)
+    ///
+    /// The master hangs up once all the slave file descriptors are closed, which doesn't require
+    /// any `Child` handle: this works the same with a master whose process was spawned by
+    /// another program.
+    ///
+    /// While paused (cf. `pause()`), this only waits for the master to hang up.
+    pub fn wait(&self) {
+        if self.paused {
+            wait_hangup(&self.master);
+            return;
+        }
+        while !self.proxy.do_flush.load(Relaxed) {
+            let _ = self.flush_event.recv();
+        }
+    }
+
+    /// Same as `wait()` but return immediately, with true if the TTY binding broke
+    pub fn try_wait(&self) -> bool {
+        if self.paused {
+            is_hangup(&self.master)
+        } else {
+            self.proxy.do_flush.load(Relaxed)
+        }
+    }
+
+    /// Check if the proxy is forwarding data, i.e. it is neither stopped, flushing nor paused
+    ///
+    /// The proxy stops forwarding once the binding broke or the client is dropped (cf.
+    /// `try_wait()`), and while it is paused (cf. `pause()`) or `query_da1()` runs.
+    pub fn is_active(&self) -> bool {
+        !self.paused && !self.proxy.do_flush.load(Relaxed)
+    }
+
+    /// Check if the proxy is paused (cf. `pause()`)
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Stop forwarding data until `resume()` is called
+    ///
+    /// The data available when pausing is still forwarded, and the next one is kept by the TTYs
+    /// meanwhile: the process blocks once the master buffer is full. The binding is not broken:
+    /// `wait()` and `try_wait()` only check the master hang up, and `end_reason()` returns `None`
+    /// until the proxy is resumed and stopped again.
+    pub fn pause(&mut self) {
+        if !self.paused && !self.proxy.do_flush.load(Relaxed) {
+            self.stop_proxy();
+            self.paused = true;
+        }
+    }
+
+    /// Restart forwarding data after a `pause()`
+    ///
+    /// On error, the proxy is left stopped.
+    pub fn resume(&mut self) -> io::Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        let (event_tx, event_rx) = channel();
+        self.proxy = try!(Proxy::new(event_tx));
+        self.flush_event = event_rx;
+        self.spawn_proxy()
+    }
+
+    /// Check if the proxy stopped because all the slave file descriptors are closed (e.g. the
+    /// connected processes exited), as opposed to an error or the peer closing
+    ///
+    /// This is only relevant once `wait()` returned. The process may still run if it closed all
+    /// its TTY file descriptors.
+    pub fn child_gone(&self) -> bool {
+        self.child_gone.load(Relaxed)
+    }
+
+    /// Get the identifier of this client, also written in the recording metadata, if any
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Get the reason why the proxy stopped, or `None` if it is still running or paused
+    ///
+    /// This is only relevant once `wait()` returned (cf. `child_gone()`).
+    pub fn end_reason(&self) -> Option<EndReason> {
+        if self.paused || !self.proxy.do_flush.load(Relaxed) {
+            None
+        } else if self.expired() {
+            Some(EndReason::MaxDuration)
+        } else if self.child_gone() {
+            Some(EndReason::ChildExit)
+        } else if self.proxy.failed() {
+            Some(EndReason::Error)
+        } else {
+            Some(EndReason::PeerClosed)
+        }
+    }
+
+    /// Check if the proxy stopped because of `TtyClientBuilder::max_duration()`
+    pub fn expired(&self) -> bool {
+        self.expired.load(Relaxed)
+    }
+
+    /// Get the error which stopped the proxy, if any (e.g. an error condition on the peer)
+    ///
+    /// The proxy also stops without error when the master or the peer is closed.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.proxy.take_error()
+    }
+
+    /// Update the terminal window size according to the peer
+    pub fn update_winsize(&mut self) {
+        let _ = self.set_winsize_from_peer();
+    }
+
+    /// Same as `update_winsize()` but report errors and only need a shared reference
+    ///
+    /// This can be called from any SIGWINCH handling, e.g. a signal-hook iterator, instead of
+    /// relying on a sigwinch_handler or a `TtyClientBuilder::resize_trigger()`.
+    pub fn set_winsize_from_peer(&self) -> io::Result<()> {
+        let ws = try!(get_winsize(&FileDesc::new(self.peer_tty, false)));
+        set_winsize(&self.master, &ws)
+    }
+
+    /// Apply a control message received in-band from the peer (cf. `ControlParser`)
+    ///
+    /// This is useful for a peer without SIGWINCH, e.g. a socket. A resize also calls the
+    /// `TtyClientBuilder::on_resize()` hook.
+    pub fn handle_control(&self, msg: ControlMsg) -> io::Result<()> {
+        match msg {
+            ControlMsg::Resize(ws) => {
+                try!(set_winsize(&self.master, &ws));
+                if let Some(ref hook) = self.on_resize {
+                    if let Ok(mut hook) = hook.lock() {
+                        (*hook)(ws);
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Get the proxy statistics
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> TtyStats {
+        match self.proxy.stats.lock() {
+            Ok(stats) => *stats,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    /// Record the output into `writer` from now on, and return the previous writer
+    ///
+    /// This enables to rotate a recording without stopping the session. The output is recorded
+    /// chunk by chunk, hence each chunk goes entirely to one of the writers. The previous writer
+    /// is flushed (and its compression stream finalized) before being returned, and the limit of
+    /// `record_output_limited()` starts again from zero for the new one. `None` is returned if
+    /// the output is not recorded.
+    pub fn rotate_output_recorder<W>(&self, writer: W) -> io::Result<Option<Box<Write + Send>>>
+            where W: Write + Send + 'static {
+        let recorder = match self.output_recorder {
+            Some(ref r) => r,
+            None => return Ok(None),
+        };
+        let mut recorder = match recorder.lock() {
+            Ok(r) => r,
+            Err(e) => e.into_inner(),
+        };
+        recorder.rotate(Box::new(writer)).map(Some)
+    }
+
+    /// Get the peer TTY configuration as it was before the client changed it, if the peer is a TTY
+    ///
+    /// This can be applied to a remote TTY with `TtyServer::apply_termios_snapshot()`.
+    pub fn peer_termios_snapshot(&self) -> Option<TermiosSnapshot> {
+        self.termios_orig.as_ref().map(TermiosSnapshot::from)
+    }
+
+    /// Save the current peer configuration (termios and file status flags)
+    ///
+    /// This is useful to coordinate with other users of the peer TTY.
+    pub fn snapshot_peer_state(&self) -> io::Result<PeerState> {
+        Ok(PeerState {
+            termios: try!(Termios::from_fd(self.peer_tty)),
+            flags: try!(get_flags(self.peer_tty)),
+        })
+    }
+
+    /// Restore a peer configuration saved with `snapshot_peer_state()`
+    ///
+    /// The proxy needs the append flag to be unset, which is the case for a state saved while
+    /// the client is running.
+    pub fn restore_peer_state(&self, state: PeerState) -> io::Result<()> {
+        try!(tcsetattr(self.peer_tty, termios::TCSANOW, &state.termios));
+        set_flags(self.peer_tty, state.flags)
+    }
+
+    /// Check if the peer TTY is in raw mode (i.e. no echo, canonical mode nor signal characters)
+    ///
+    /// This can detect another user of the peer TTY resetting its configuration during the
+    /// session. A peer which is not a TTY is never raw.
+    pub fn is_peer_raw(&self) -> io::Result<bool> {
+        if !is_tty(self.peer_tty) {
+            return Ok(false);
+        }
+        let termios_peer = try!(Termios::from_fd(self.peer_tty));
+        Ok(termios_peer.c_lflag & (termios::ECHO | termios::ICANON | termios::ISIG) == 0)
+    }
+
+    /// Check if the peer is a terminal, i.e. either its input or its output is a TTY
+    pub fn peer_is_terminal(&self) -> bool {
+        is_tty(self.peer_tty)
+    }
+
+    /// Send a primary device attributes request (DA1) to the peer and get the parameters of its
+    /// answer, e.g. `[62, 22]` for a VT220 compatible terminal supporting ANSI color
+    ///
+    /// The proxy is paused meanwhile, and the peer input read along the answer is forwarded to
+    /// the master. A `TimedOut` error is returned if the peer didn't answer before `timeout`.
+    pub fn query_da1(&mut self, timeout: Duration) -> io::Result<Vec<u32>> {
+        if self.proxy.do_flush.load(Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "The proxy is stopped"));
+        }
+        self.stop_proxy();
+        let answer = query_da1(self.peer.as_raw_fd(), self.peer_out_fd(), self.master.as_raw_fd(), timeout);
+        let (event_tx, event_rx) = channel();
+        self.proxy = try!(Proxy::new(event_tx));
+        self.flush_event = event_rx;
+        try!(self.spawn_proxy());
+        answer
+    }
+
+    /// Write a reset sequence to the peer, e.g. to leave an alternate screen or charset set by the
+    /// session, which is not part of the TTY configuration
+    ///
+    /// This is a soft reset (DECSTR) which also leaves the alternate screen, shows the cursor and
+    /// disables the mouse tracking and the bracketed paste, without clearing the screen.
+    pub fn reset_peer_display(&self) -> io::Result<()> {
+        write_all_fd(self.peer_out_fd(), RESET_DISPLAY)
+    }
+
+    fn peer_out_fd(&self) -> RawFd {
+        self.peer_out.as_ref().unwrap_or(&self.peer).as_raw_fd()
+    }
+
+    // Either a pipe for splice(2) or a buffer pool for read(2) and write(2)
+    fn new_forward(&self) -> io::Result<Forward> {
+        match self.buffer_pool {
+            Some(ref pool) => Ok(Forward::Copy(pool.clone())),
+            None => Ok(Forward::Splice(try!(new_pipe(self.config.pipe_size)))),
+        }
+    }
+
+    // Start the proxy threads according to the current peer
+    fn spawn_proxy(&mut self) -> io::Result<()> {
+        let peer_in_fd = self.peer.as_raw_fd();
+        let peer_out_fd = self.peer_out_fd();
+        let master_fd = self.master.as_raw_fd();
+
+        // Create all the pipes first to not leave a partial proxy
+        let m2p = if self.config.output {
+            Some(try!(self.new_forward()))
+        } else {
+            None
+        };
+        let p2m = if self.config.input {
+            Some(try!(self.new_forward()))
+        } else {
+            None
+        };
+
+        let tap = match (&m2p, &self.output_recorder) {
+            (&Some(_), &Some(_)) => Some(try!(new_pipe(self.config.pipe_size))),
+            _ => None,
+        };
+        let mut input_hooks = Vec::new();
+        if let Some(ref hook) = self.on_input {
+            input_hooks.push(hook.clone());
+        }
+        // The echo must not wait for the paste boundaries to be complete
+        let mut raw_input_hooks = Vec::new();
+        if self.config.local_echo {
+            raw_input_hooks.push(echo_hook(master_fd, peer_out_fd, self.config.local_echo_crlf));
+        }
+        let input_tap = if p2m.is_some() && !(input_hooks.is_empty() && raw_input_hooks.is_empty()) {
+            Some(try!(new_pipe(self.config.pipe_size)))
+        } else {
+            None
+        };
+
+        if let Some(m2p) = m2p {
+            // Master to peer
+            let tap_tx = match (tap, &self.output_recorder) {
+                (Some(t), Some(recorder)) => {
+                    self.proxy_threads.push(spawn_recorder(recorder.clone(), t.reader));
+                    Some(t.writer)
+                },
+                _ => None,
+            };
+            let output = match m2p {
+                Forward::Splice(p) => {
+                    let (m2p_tx, m2p_rx) = (p.writer, p.reader);
+                    self.proxy_threads.push(self.proxy.spawn_splice_loop(master_fd, m2p_tx.as_raw_fd(), m2p_tx,
+                                                                           None, Some(self.child_gone.clone())));
+                    self.proxy.spawn_splice_loop(m2p_rx.as_raw_fd(), peer_out_fd, m2p_rx, tap_tx, None)
+                },
+                Forward::Copy(pool) => self.proxy.spawn_copy_loop(master_fd, peer_out_fd, pool, tap_tx,
+                                                                  Some(self.child_gone.clone())),
+            };
+            if self.peer_out.is_some() {
+                // Let a pipe reader (e.g. another process) see the end of the output right away,
+                // without waiting for the client to be dropped
+                let child_gone = self.child_gone.clone();
+                self.proxy_threads.push(thread::spawn(move || {
+                    let _ = output.join();
+                    if child_gone.load(Relaxed) {
+                        let _ = close_pipe_writer(peer_out_fd);
+                    }
+                }));
+            } else {
+                self.proxy_threads.push(output);
+            }
+        }
+
+        if let Some(p2m) = p2m {
+            // Peer to master
+            let tap_tx = match input_tap {
+                Some(t) => {
+                    self.proxy_threads.push(spawn_input_tap(raw_input_hooks, input_hooks, t.reader));
+                    Some(t.writer)
+                },
+                None => None,
+            };
+            match p2m {
+                Forward::Splice(p) => {
+                    let (p2m_tx, p2m_rx) = (p.writer, p.reader);
+                    self.proxy_threads.push(self.proxy.spawn_splice_loop(peer_in_fd, p2m_tx.as_raw_fd(), p2m_tx,
+                                                                           None, None));
+                    self.proxy_threads.push(self.proxy.spawn_splice_loop(p2m_rx.as_raw_fd(), master_fd, p2m_rx,
+                                                                           tap_tx, None));
+                },
+                Forward::Copy(pool) => {
+                    self.proxy_threads.push(self.proxy.spawn_copy_loop(peer_in_fd, master_fd, pool, tap_tx, None));
+                },
+            }
+        }
+
+        if let Some(interval) = self.config.watchdog {
+            let mut inputs = Vec::new();
+            if self.config.output {
+                inputs.push(master_fd);
+            }
+            if self.config.input {
+                inputs.push(peer_in_fd);
+            }
+            self.proxy_threads.push(self.proxy.spawn_watchdog(interval, inputs));
+        }
+
+        if let Some((deadline, hangup)) = self.max_duration {
+            // Joined with the proxy threads to not use the master once closed
+            let proxy = self.proxy.clone();
+            let expired = self.expired.clone();
+            self.proxy_threads.push(thread::spawn(move || {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                if !proxy.wait_flush_request(timeout) {
+                    expired.store(true, Relaxed);
+                    if hangup {
+                        let mut pgrp: pid_t = 0;
+                        if unsafe { libc::ioctl(master_fd, libc::TIOCGPGRP, &mut pgrp) } != -1 && pgrp > 0 {
+                            let _ = unsafe { libc::kill(-pgrp, libc::SIGHUP) };
+                        }
+                    }
+                    proxy.request_flush();
+                }
+            }));
+        }
+
+        self.spawn_resizers();
+        Ok(())
+    }
+
+    // (Re)start the terminal resizing threads according to the current peer
+    fn spawn_resizers(&mut self) {
+        let master_fd = self.master.as_raw_fd();
+        let (stop_tx, stop_rx) = chan::sync(0);
+        if let Some(signal) = self.sigwinch_handler.clone() {
+            let mut resizer = Resizer::new(master_fd, self.peer_tty, self.on_resize.clone(),
+                                           self.config.resize_debounce);
+            let other_signals = self.other_signals.clone();
+            let stop_rx = stop_rx.clone();
+            thread::spawn(move || {
+                'select: loop {
+                    let deadline = resizer.deadline();
+                    chan_select! {
+                        signal.recv() -> signal => {
+                            let signal = match signal {
+                                Some(s) => s,
+                                // No more signal will come once all the senders are dropped
+                                None => {
+                                    resizer.flush();
+                                    break;
+                                },
+                            };
+                            if signal != Signal::WINCH {
+                                if let Some(ref other) = other_signals {
+                                    other.send(signal);
+                                }
+                                continue 'select;
+                            }
+                            resizer.request();
+                        },
+                        deadline.recv() => {
+                            resizer.timeout();
+                        },
+                        stop_rx.recv() => {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+        if let Some(trigger) = self.resize_trigger.clone() {
+            let mut resizer = Resizer::new(master_fd, self.peer_tty, self.on_resize.clone(),
+                                           self.config.resize_debounce);
+            thread::spawn(move || {
+                loop {
+                    let deadline = resizer.deadline();
+                    chan_select! {
+                        trigger.recv() -> event => {
+                            if event.is_none() {
+                                resizer.flush();
+                                break;
+                            }
+                            resizer.request();
+                        },
+                        deadline.recv() => {
+                            resizer.timeout();
+                        },
+                        stop_rx.recv() => {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+        // Stop the previous resizing threads, if any
+        self._stop = stop_tx;
+    }
+
+    /// Update the terminal window size on SIGWINCH using the signal-hook crate
+    ///
+    /// Unlike the sigwinch_handler from chan_signal, the signal handler can be registered at any
+    /// time, even after other threads are spawned. This replaces any
+    /// `TtyClientBuilder::resize_trigger()`.
+    #[cfg(feature = "signal-hook")]
+    pub fn with_signal_hook_winch(mut self) -> io::Result<TtyClient> {
+        let mut signals = try!(signal_hook::iterator::Signals::new([signal_hook::consts::SIGWINCH]));
+        let (trigger_tx, trigger_rx) = chan::async();
+        self.signal_hook = Some(signals.handle());
+        // Stop when the handle is closed by TtyClient::drop()
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                trigger_tx.send(());
+            }
+        });
+        self.resize_trigger = Some(trigger_rx);
+        self.spawn_resizers();
+        Ok(self)
+    }
+
+    // Flush and wait for the proxy threads
+    fn stop_proxy(&mut self) {
+        self.proxy.request_flush();
+        // The proxy threads must not use the file descriptors once closed
+        for thread in self.proxy_threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+
+    // Restore the peer configuration changed by setup_peer()
+    fn restore_peer(&self) {
+        if let Some(ref termios_orig) = self.termios_orig {
+            let _ = tcsetattr(self.peer_tty, self.config.restore_action.as_raw(), termios_orig);
+        }
+        // Set if the append flag was unset
+        if self.peer_status.is_some() {
+            let _ = restore_append_flag(self.peer_out_fd());
+        }
+    }
+
+    /// Replace the peer with `new_peer` without stopping the process
+    ///
+    /// The proxy is stopped, the current peer is restored and closed, then the new one is
+    /// configured and the proxy restarted. The output already read from the master is flushed
+    /// to the current peer whereas the pending output is forwarded to the new one.
+    ///
+    /// On error, the proxy is left stopped.
+    pub fn rebind_peer<T>(&mut self, new_peer: T) -> io::Result<()> where T: AsRawFd + IntoRawFd {
+        self.stop_proxy();
+        self.paused = false;
+        self.restore_peer();
+        let peer = FileDesc::new(new_peer.into_raw_fd(), true);
+        let peer_tty = find_peer_tty(&peer, &None);
+        let (termios_orig, peer_status) = try!(self.config.setup_peer(peer_tty, &peer, &None));
+        self.peer = peer;
+        self.peer_out = None;
+        self.peer_tty = peer_tty;
+        self.termios_orig = termios_orig;
+        self.peer_status = peer_status;
+
+        let (event_tx, event_rx) = channel();
+        self.proxy = try!(Proxy::new(event_tx));
+        self.flush_event = event_rx;
+        self.spawn_proxy()
+    }
+}
+
+impl Drop for TtyClient {
+    /// Cleanup the peer TTY
+    ///
+    /// The proxy is first asked to flush and waited for, then the peer TTY configuration is
+    /// restored, and finally the peer and the master file descriptors are closed, in this order.
+    ///
+    /// The proxy threads are woken up even if blocked on an idle peer (e.g. the real stdin), and
+    /// only forward the data already available, which restores the peer TTY right away.
+    fn drop(&mut self) {
+        #[cfg(feature = "signal-hook")]
+        {
+            if let Some(ref handle) = self.signal_hook {
+                handle.close();
+            }
+        }
+        self.stop_proxy();
+        if let Some(ref recorder) = self.output_recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.finish();
+            }
+        }
+        if self.config.reset_display {
+            let _ = self.reset_peer_display();
+        }
+        self.restore_peer();
+
+        // Restore the append flag if needed
+        if self.master_status.is_some() {
+            let _ = restore_append_flag(self.master.as_raw_fd());
+        }
+
+        if let Some(ref ws) = self.winsize_orig {
+            // Stop the resizing threads first
+            let (stop_tx, _) = chan::sync(0);
+            self._stop = stop_tx;
+            let _ = set_winsize(&self.master, ws);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, panic, process};
+    use std::os::unix::fs::symlink;
+
+    fn shell(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    // Read the master until all the slave file descriptors are closed
+    fn read_output(server: &TtyServer) -> Vec<u8> {
+        let mut master = server.get_master();
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match master.read(&mut buf) {
+                Ok(0) | Err(_) => return out,
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    // Make the slave the controlling terminal of the process
+    fn ctty() -> SpawnOptions {
+        SpawnOptions::new().before_exec(Box::new(|| {
+            match unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }))
+    }
+
+    // Check that `reader` gets `expected` within a few seconds
+    fn read_until(mut reader: File, expected: &'static [u8]) -> bool {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(n) = reader.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+                if out.windows(expected.len()).any(|w| w == expected) {
+                    let _ = tx.send(());
+                    return;
+                }
+            }
+        });
+        rx.recv_timeout(Duration::from_secs(5)).is_ok()
+    }
+
+    #[test]
+    fn spawn_streams() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("test -t 0 && echo out && echo err >&2")).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"out\r\nerr\r\n");
+    }
+
+    #[test]
+    fn spawn_stderr() {
+        let mut server = TtyServer::new_default().unwrap();
+        let options = SpawnOptions::new().stderr(Stdio::piped());
+        let child = server.spawn_with(shell("echo out && echo err >&2"), options).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stderr, b"err\n");
+        assert_eq!(read_output(&server), b"out\r\n");
+    }
+
+    #[test]
+    fn spawn_close_stdin() {
+        let mut server = TtyServer::new_default().unwrap();
+        let options = SpawnOptions::new().close_stdin(true);
+        let mut child = server.spawn_with(shell("test ! -t 0 && test -t 1 && echo ok"), options)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"ok\r\n");
+    }
+
+    #[test]
+    fn spawn_keep_slave() {
+        let mut server = TtyServer::new_default().unwrap();
+        for msg in &["first", "second"] {
+            let mut child = server.spawn_keep_slave(shell(&format!("echo {}", msg)),
+                                                    SpawnOptions::new()).unwrap();
+            assert!(child.wait().unwrap().success());
+        }
+        assert!(server.slave().is_some());
+        assert!(!server.slave_hangup_detected());
+        drop(server.take_slave());
+        assert_eq!(read_output(&server), b"first\r\nsecond\r\n");
+    }
+
+    #[test]
+    fn resize_before_spawn() {
+        let mut server = TtyServer::new_default().unwrap();
+        // The size must also reach a reopened slave
+        drop(server.take_slave());
+        let peer = TtyServer::new_raw(Some(&WinSize::from((100, 30)))).unwrap();
+        server.sync_winsize_from(peer.get_master()).unwrap();
+        let mut child = server.spawn(shell("stty size")).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"30 100\r\n");
+    }
+
+    #[test]
+    fn slave_borrow() {
+        let mut server = TtyServer::new_default().unwrap();
+        {
+            let slave = server.slave().unwrap();
+            let mut termios = Termios::from_fd(slave.as_raw_fd()).unwrap();
+            termios.c_oflag &= !termios::OPOST;
+            tcsetattr(slave.as_raw_fd(), termios::TCSANOW, &termios).unwrap();
+        }
+        let mut child = server.spawn(shell("echo hi")).unwrap();
+        assert!(server.slave().is_none());
+        assert!(child.wait().unwrap().success());
+        // No output processing, hence no carriage return
+        assert_eq!(read_output(&server), b"hi\n");
+    }
+
+    #[test]
+    fn concurrent_servers() {
+        let threads: Vec<_> = (0..8).map(|_| thread::spawn(|| {
+            (0..16).map(|_| {
+                let server = TtyServer::new_default().unwrap();
+                // The path must be the one of the slave opened with the master
+                let fd = format!("/proc/self/fd/{}", server.slave().unwrap().as_raw_fd());
+                assert_eq!(fs::read_link(fd).unwrap(), server.as_ref());
+                server
+            }).collect::<Vec<_>>()
+        })).collect();
+        let servers: Vec<_> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        let mut paths: Vec<_> = servers.iter().map(|s| s.as_ref().to_path_buf()).collect();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), servers.len());
+    }
+
+    #[test]
+    fn attach_after_spawn() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("echo early")).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let mut peer = TtyServer::new_default().unwrap();
+        let _client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(read_until(peer.get_master().try_clone().unwrap(), b"early"));
+    }
+
+    #[test]
+    fn client_outlives_server() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        drop(server);
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        viewer.write_all(b"hi\n").unwrap();
+        assert!(read_until(viewer, b"got hi"));
+        assert!(child.wait().unwrap().success());
+        client.wait();
+    }
+
+    #[test]
+    fn master_halves() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let (mut reader, mut writer) = server.master_halves().unwrap();
+        drop(server);
+        thread::spawn(move || writer.write_all(b"hi\n").unwrap()).join().unwrap();
+        assert!(child.wait().unwrap().success());
+        // The hang up is an end of file instead of an error
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hi\r\ngot hi\r\n");
+    }
+
+    #[test]
+    fn local_echo() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let mut child = server.spawn(shell("sleep 5")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().local_echo(true);
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        let (tx, rx) = channel();
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        // A trailing escape and the paste boundaries are echoed right away
+        let input = b"a\x1b[200~b\x1b[201~\x1b";
+        viewer.write_all(input).unwrap();
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(n) = viewer.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+                if out.len() >= input.len() {
+                    let _ = tx.send(out);
+                    return;
+                }
+            }
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), input);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        drop(client);
+    }
+
+    #[test]
+    fn translate_cr() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().translate_cr(true);
+        let _client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        // The raw slave would not end the line on its own
+        viewer.write_all(b"hi\r").unwrap();
+        assert!(read_until(viewer, b"got hi"));
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn termios_snapshot() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let peer_slave = peer.take_slave().unwrap();
+        let mut termios = Termios::from_fd(peer_slave.as_raw_fd()).unwrap();
+        termios.c_lflag &= !termios::ECHOE;
+        termios.c_cc[termios::VINTR] = 1;
+        tcsetattr(peer_slave.as_raw_fd(), termios::TCSANOW, &termios).unwrap();
+        let expected = TermiosSnapshot::from(&termios);
+
+        let client = server.new_client(peer_slave, None).unwrap();
+        // The peer is now in raw mode but the snapshot is the original configuration
+        let snapshot = client.peer_termios_snapshot().unwrap();
+        assert_eq!(snapshot, expected);
+
+        let remote = TtyServer::new_raw(None).unwrap();
+        remote.apply_termios_snapshot(&snapshot).unwrap();
+        let termios_remote = Termios::from_fd(remote.get_master().as_raw_fd()).unwrap();
+        assert_eq!(TermiosSnapshot::from(&termios_remote), expected);
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let pool = BufferPool::new(2, 16);
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().buffer_pool(pool.clone());
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        assert!(pool.is_empty());
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        viewer.write_all(b"hi\n").unwrap();
+        assert!(read_until(viewer, b"got hi"));
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        drop(client);
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn resize_debounce() {
+        let server = TtyServer::new_default().unwrap();
+        let peer = TtyServer::new_default().unwrap();
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let hook_sizes = sizes.clone();
+        let hook: ResizeHook = Box::new(move |ws| hook_sizes.lock().unwrap().push(ws));
+        let mut resizer = Resizer::new(server.get_master().as_raw_fd(), peer.get_master().as_raw_fd(),
+                                       Some(Arc::new(Mutex::new(hook))), Some(Duration::from_millis(50)));
+        for cols in 1..101 {
+            set_winsize(peer.get_master(), &WinSize::from((cols, 24))).unwrap();
+            resizer.request();
+        }
+        while resizer.due.is_some() {
+            resizer.deadline().recv();
+            resizer.timeout();
+        }
+        let last = WinSize::from((100, 24));
+        assert_eq!(*sizes.lock().unwrap(), vec![last]);
+        assert_eq!(get_winsize(server.get_master()).unwrap(), last);
+    }
+
+    // Wait for `check` to hold within a few seconds
+    fn wait_for<F>(mut check: F) -> bool where F: FnMut() -> bool {
+        for _ in 0..500 {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn signal_close() {
+        let mut server = TtyServer::new_default().unwrap();
+        let (sigchld_tx, sigchld_rx) = chan::async();
+        server.enable_auto_reap(sigchld_rx);
+        drop(sigchld_tx);
+        // The reaper thread drops its state once stopped
+        let state = server.reaper.as_ref().unwrap().state.clone();
+        assert!(wait_for(|| Arc::strong_count(&state) == 2));
+
+        let mut peer = TtyServer::new_default().unwrap();
+        let (other_tx, other_rx) = chan::async();
+        let builder = TtyClientBuilder::new().resize_debounce(Duration::from_secs(60))
+            .other_signals(other_tx);
+        let (winch_tx, winch_rx) = chan::async();
+        let _client = server.new_client_with(builder, peer.take_slave().unwrap(),
+                                             Some(winch_rx)).unwrap();
+        let ws = WinSize::from((100, 30));
+        set_winsize(peer.get_master(), &ws).unwrap();
+        winch_tx.send(Signal::HUP);
+        winch_tx.send(Signal::WINCH);
+        drop(winch_tx);
+        assert_eq!(other_rx.recv(), Some(Signal::HUP));
+        // The pending resize is applied without waiting for the debounce delay
+        assert!(wait_for(|| get_winsize(server.get_master()).unwrap() == ws));
+    }
+
+    // Check that a connection sending `input` to a served `script` gets `expected` back
+    fn serve_once(script: &'static str, resize: TcpResize, input: &[u8], expected: &[u8]) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_tcp_with(&listener, || shell(script), resize, |_| {}));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(input).unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+            if out.windows(expected.len()).any(|w| w == expected) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn serve_tcp_fixed() {
+        assert!(serve_once("echo hi", TcpResize::Fixed, b"", b"hi\r\n"));
+    }
+
+    #[test]
+    fn serve_tcp_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let cmd_factory = || Command::new("/nonexistent");
+        thread::spawn(move || serve_tcp_with(&listener, cmd_factory, TcpResize::Fixed, move |e| {
+            let _ = tx.lock().unwrap().send(e.kind());
+        }));
+        let _stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn serve_tcp_control() {
+        let mut input = ControlMsg::Resize(WinSize::from((100, 30))).to_bytes();
+        input.extend_from_slice(b"\n");
+        assert!(serve_once("read l && stty size", TcpResize::Control, &input, b"30 100\r\n"));
+    }
+
+    #[test]
+    fn pause_resume() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let mut client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(client.is_active());
+        client.pause();
+        assert!(client.is_paused());
+        assert!(!client.is_active());
+        // The binding is not broken
+        assert!(!client.try_wait());
+        assert_eq!(client.end_reason(), None);
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        viewer.write_all(b"hi\n").unwrap();
+        client.resume().unwrap();
+        assert!(!client.is_paused());
+        assert!(client.is_active());
+        assert!(read_until(viewer, b"got hi"));
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        assert!(!client.is_active());
+
+        // The process exits while paused
+        let mut child = server.spawn(shell("sleep 0.2; echo bye")).unwrap();
+        let mut client = server.new_client(peer.open_slave().unwrap(), None).unwrap();
+        client.pause();
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        assert!(client.try_wait());
+        assert_eq!(client.end_reason(), None);
+        client.resume().unwrap();
+        client.wait();
+        assert_eq!(client.end_reason(), Some(EndReason::ChildExit));
+    }
+
+    #[test]
+    fn auto_reap() {
+        let mut server = TtyServer::new_default().unwrap();
+        let (sigchld_tx, sigchld_rx) = chan::async();
+        server.enable_auto_reap(sigchld_rx);
+        // Reaped by the server instead
+        #[allow(clippy::zombie_processes)]
+        let _child = server.spawn(shell("exit 3")).unwrap();
+        // The slave is closed once the process exited
+        read_output(&server);
+        let state = server.reaper.as_ref().unwrap().state.clone();
+        // Other signals are ignored
+        sigchld_tx.send(Signal::HUP);
+        assert!(state.lock().unwrap().status.is_none());
+        // The slave may be closed before the process becomes a zombie
+        assert!(wait_for(|| {
+            sigchld_tx.send(Signal::CHLD);
+            state.lock().unwrap().status.is_some()
+        }));
+        assert_eq!(server.child_status().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn flow_control() {
+        let ixon = |fd: &File| Termios::from_fd(fd.as_raw_fd()).unwrap().c_iflag & termios::IXON != 0;
+        for &(flow, slave) in &[(FlowControl::Slave, true), (FlowControl::Disabled, false)] {
+            let server = TtyServer::new_raw(None).unwrap();
+            let mut peer = TtyServer::new_default().unwrap();
+            let builder = TtyClientBuilder::new().flow_control(flow);
+            let _client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+            assert_eq!(ixon(server.get_master()), slave);
+            // The peer forwards XON/XOFF instead of handling them
+            assert!(!ixon(peer.get_master()));
+        }
+        let server = TtyServer::new_raw(None).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let _client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(!ixon(server.get_master()));
+        assert!(ixon(peer.get_master()));
+    }
+
+    #[test]
+    fn drop_restores_peer() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let lflag = |fd: &File| Termios::from_fd(fd.as_raw_fd()).unwrap().c_lflag;
+        let orig = lflag(peer.get_master());
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert_eq!(lflag(peer.get_master()) & termios::ECHO, 0);
+        drop(client);
+        assert_eq!(lflag(peer.get_master()), orig);
+        // The peer is closed along with the client
+        assert!(peer.slave_hangup_detected());
+    }
+
+    #[test]
+    fn drop_flushes_output() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("head -c 100000 /dev/zero | tr '\\0' a")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        // Stop once the client closed the peer
+        let reader = thread::spawn(move || {
+            let mut out = Vec::new();
+            let _ = viewer.read_to_end(&mut out);
+            out
+        });
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        drop(client);
+        let out = reader.join().unwrap();
+        assert_eq!(out.len(), 100000);
+        assert!(out.iter().all(|&c| c == b'a'));
+    }
+
+    #[test]
+    fn drop_idle_client() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        // Neither the process nor the peer have anything to forward
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            drop(client);
+            let _ = tx.send(());
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn new_raw() {
+        let ws = WinSize::from((100, 30));
+        let mut server = TtyServer::new_raw(Some(&ws)).unwrap();
+        assert_eq!(get_winsize(server.get_master()).unwrap(), ws);
+        let mut child = server.spawn(shell("head -c 4")).unwrap();
+        // Neither echoed, translated nor interpreted as a signal
+        server.get_master().write_all(b"a\r\n\x03").unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"a\r\n\x03");
+    }
+
+    #[test]
+    fn peer_state() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        let state = client.snapshot_peer_state().unwrap();
+        // Another user of the peer resets its configuration
+        let mut termios = Termios::from_fd(peer.get_master().as_raw_fd()).unwrap();
+        let raw_lflag = termios.c_lflag;
+        termios.c_lflag |= termios::ECHO | termios::ICANON;
+        tcsetattr(peer.get_master().as_raw_fd(), termios::TCSANOW, &termios).unwrap();
+        client.restore_peer_state(state).unwrap();
+        assert_eq!(Termios::from_fd(peer.get_master().as_raw_fd()).unwrap().c_lflag, raw_lflag);
+    }
+
+    #[test]
+    fn new_at() {
+        // A devpts multiplexer reachable from another directory
+        let dir = env::temp_dir().join(format!("tty-new-at-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let _ = fs::remove_file(dir.join("ptmx"));
+        symlink("/dev/ptmx", dir.join("ptmx")).unwrap();
+        let template = TtyServer::new_raw(Some(&WinSize::from((100, 30)))).unwrap();
+        let mut server = TtyServer::new_at(&dir, Some(template.get_master())).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(server.as_ref().parent(), Some(dir.as_path()));
+        let mut child = server.spawn(shell("stty size")).unwrap();
+        assert!(child.wait().unwrap().success());
+        // Raw mode from the template
+        assert_eq!(read_output(&server), b"30 100\n");
+    }
+
+    #[test]
+    fn new_split() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l && echo got $l")).unwrap();
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let _client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        let mut input = input.writer;
+        input.write_all(b"hi\n").unwrap();
+        assert!(read_until(output.reader, b"got hi\r\n"));
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn rebind_peer() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read a && echo got $a && read b && echo got $b")).unwrap();
+        let mut first = TtyServer::new_default().unwrap();
+        let mut client = server.new_client(first.take_slave().unwrap(), None).unwrap();
+        let mut viewer = first.get_master().try_clone().unwrap();
+        viewer.write_all(b"one\n").unwrap();
+        assert!(read_until(viewer, b"got one"));
+
+        let mut second = TtyServer::new_default().unwrap();
+        client.rebind_peer(second.take_slave().unwrap()).unwrap();
+        // The previous peer is restored and closed
+        let lflag = Termios::from_fd(first.get_master().as_raw_fd()).unwrap().c_lflag;
+        assert_ne!(lflag & termios::ECHO, 0);
+        assert!(first.slave_hangup_detected());
+        let mut viewer = second.get_master().try_clone().unwrap();
+        viewer.write_all(b"two\n").unwrap();
+        assert!(read_until(viewer, b"got two"));
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn restore_append_flag() {
+        let server = TtyServer::new_default().unwrap();
+        let path = env::temp_dir().join(format!("tty-append-{}", process::id()));
+        let output = OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let output_fd = output.try_clone().unwrap();
+        let input = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let client = TtyClient::new_split(master, input.reader, output, None).unwrap();
+        let flags = get_flags(output_fd.as_raw_fd()).unwrap();
+        assert_eq!(flags & libc::O_APPEND, 0);
+        // Another user of the open file description changes its flags meanwhile
+        set_flags(output_fd.as_raw_fd(), flags | libc::O_NONBLOCK).unwrap();
+        drop(client);
+        let flags = get_flags(output_fd.as_raw_fd()).unwrap();
+        assert_eq!(flags & (libc::O_APPEND | libc::O_NONBLOCK), libc::O_APPEND | libc::O_NONBLOCK);
+    }
+
+    #[test]
+    fn into_pty() {
+        let server = TtyServer::new_default().unwrap();
+        let path = server.as_ref().to_path_buf();
+        let pty = server.into_pty().unwrap();
+        assert_eq!(pty.path, path);
+        let mut server = TtyServer::from_pty(pty);
+        assert_eq!(server.as_ref(), path.as_path());
+        drop(server.take_slave());
+        // The slave is required
+        assert_eq!(server.into_pty().err().unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn resize_trigger() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let (trigger_tx, trigger_rx) = chan::async();
+        let builder = TtyClientBuilder::new().resize_trigger(trigger_rx);
+        let _client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        let ws = WinSize::from((100, 30));
+        set_winsize(peer.get_master(), &ws).unwrap();
+        trigger_tx.send(());
+        assert!(wait_for(|| get_winsize(server.get_master()).unwrap() == ws));
+    }
+
+    #[cfg(feature = "signal-hook")]
+    #[test]
+    fn signal_hook_winch() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let _client = server.new_client(peer.take_slave().unwrap(), None).unwrap()
+            .with_signal_hook_winch().unwrap();
+        let ws = WinSize::from((100, 30));
+        set_winsize(peer.get_master(), &ws).unwrap();
+        // SIGWINCH is ignored by default, and only this client handles it
+        assert_eq!(unsafe { libc::raise(libc::SIGWINCH) }, 0);
+        assert!(wait_for(|| get_winsize(server.get_master()).unwrap() == ws));
+    }
+
+    #[test]
+    fn record_output_to_path() {
+        let dir = env::temp_dir().join(format!("tty-record-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(TtyClientBuilder::new().record_output_to_path(dir.join("output")).is_err());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output");
+        fs::write(&path, "previous recording").unwrap();
+
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("echo hi")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().record_output_to_path(&path).unwrap();
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        drop(client);
+        let recording = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(recording, b"hi\r\n");
+    }
+
+    #[test]
+    fn is_peer_raw() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(client.is_peer_raw().unwrap());
+        // Another user of the peer resets its configuration
+        let mut termios = Termios::from_fd(peer.get_master().as_raw_fd()).unwrap();
+        termios.c_lflag |= termios::ECHO;
+        tcsetattr(peer.get_master().as_raw_fd(), termios::TCSANOW, &termios).unwrap();
+        assert!(!client.is_peer_raw().unwrap());
+        drop(client);
+
+        // A peer which is not a TTY
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        assert!(!client.is_peer_raw().unwrap());
+    }
+
+    #[test]
+    fn query_da1() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let mut child = server.spawn(shell("head -c 2")).unwrap();
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let mut client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        assert!(!client.peer_is_terminal());
+        let (mut terminal_in, mut terminal_out) = (input.writer, output.reader);
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            let mut buf = [0; 1024];
+            let mut answered = false;
+            while let Ok(n) = terminal_out.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+                let requests = out.windows(3).filter(|w| w == b"\x1b[c").count();
+                // Only answer the second request, with some user input around
+                if requests == 2 && !answered {
+                    terminal_in.write_all(b"x\x1b[?62;22cy").unwrap();
+                    answered = true;
+                }
+                if out.windows(2).any(|w| w == b"xy") {
+                    let _ = tx.send(());
+                    return;
+                }
+            }
+        });
+        let err = client.query_da1(Duration::from_millis(100)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.query_da1(Duration::from_secs(5)).unwrap(), vec![62, 22]);
+        // The user input is forwarded without the answer
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(child.wait().unwrap().success());
+
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(client.peer_is_terminal());
+    }
+
+    #[test]
+    fn spawn_fds() {
+        let mut server = TtyServer::new_default().unwrap();
+        let options = SpawnOptions::new().map_fd(3, libc::STDOUT_FILENO);
+        let err = server.spawn_with(shell("true"), options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let (first, second) = (new_pipe(None).unwrap(), new_pipe(None).unwrap());
+        let (fd1, fd2) = (first.writer.as_raw_fd(), second.writer.as_raw_fd());
+        // Swap the file descriptors
+        let options = SpawnOptions::new().map_fd(fd1, fd2).map_fd(fd2, fd1);
+        // The shell redirections may only support one digit
+        let script = format!("echo one >/dev/fd/{} && echo two >/dev/fd/{}", fd2, fd1);
+        let mut child = server.spawn_with(shell(&script), options).unwrap();
+        assert!(child.wait().unwrap().success());
+        drop((first.writer, second.writer));
+        let (mut out1, mut out2) = (String::new(), String::new());
+        let (mut reader1, mut reader2) = (first.reader, second.reader);
+        reader1.read_to_string(&mut out1).unwrap();
+        reader2.read_to_string(&mut out2).unwrap();
+        assert_eq!((out1.as_str(), out2.as_str()), ("one\n", "two\n"));
+
+        let status = new_pipe(None).unwrap();
+        let options = SpawnOptions::new().keep_fd(status.writer.as_raw_fd());
+        let script = format!("echo ok >/dev/fd/{}", status.writer.as_raw_fd());
+        let mut child = server.spawn_with(shell(&script), options).unwrap();
+        assert!(child.wait().unwrap().success());
+        drop(status.writer);
+        let mut out = String::new();
+        let mut reader = status.reader;
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "ok\n");
+    }
+
+    #[test]
+    fn speed() {
+        let server = TtyServer::new_default().unwrap();
+        server.set_speed(115200).unwrap();
+        assert_eq!(server.speed().unwrap(), 115200);
+        let err = server.set_speed(12345).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(server.speed().unwrap(), 115200);
+    }
+
+    #[test]
+    fn setsid() {
+        let script = "test $(cut -d ' ' -f 6 /proc/$$/stat) = $$ && echo leader || echo member";
+        for &(setsid, expected) in &[(true, &b"leader\r\n"[..]), (false, &b"member\r\n"[..])] {
+            let mut server = TtyServer::new_default().unwrap();
+            let options = SpawnOptions::new().setsid(setsid);
+            let mut child = server.spawn_with(shell(script), options).unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(read_output(&server), expected);
+        }
+    }
+
+    #[test]
+    fn before_exec() {
+        let mut server = TtyServer::new_default().unwrap();
+        let pipe = new_pipe(None).unwrap();
+        let fd = pipe.writer.as_raw_fd();
+        let options = SpawnOptions::new()
+            .before_exec(Box::new(move || write_all_fd(fd, b"1")))
+            .before_exec(Box::new(move || write_all_fd(fd, b"2")));
+        let mut child = server.spawn_with(shell("true"), options).unwrap();
+        assert!(child.wait().unwrap().success());
+        drop(pipe.writer);
+        let mut out = Vec::new();
+        let mut reader = pipe.reader;
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"12");
+
+        let options = SpawnOptions::new()
+            .before_exec(Box::new(|| Err(io::Error::from_raw_os_error(libc::EPERM))));
+        let err = server.spawn_with(shell("true"), options).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    }
+
+    #[test]
+    fn on_resize() {
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let (trigger_tx, trigger_rx) = chan::async();
+        let (size_tx, size_rx) = channel();
+        let size_tx = Mutex::new(size_tx);
+        let builder = TtyClientBuilder::new().resize_trigger(trigger_rx)
+            .on_resize(Box::new(move |ws| { let _ = size_tx.lock().unwrap().send(ws); }));
+        let _client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        let ws = WinSize::from((100, 30));
+        set_winsize(peer.get_master(), &ws).unwrap();
+        trigger_tx.send(());
+        assert_eq!(size_rx.recv_timeout(Duration::from_secs(5)).unwrap(), ws);
+        // The master is already resized
+        assert_eq!(get_winsize(server.get_master()).unwrap(), ws);
+    }
+
+    #[test]
+    fn export_size() {
+        let mut server = TtyServer::new_raw(Some(&WinSize::from((100, 30)))).unwrap();
+        let options = SpawnOptions::new().export_size(true);
+        let mut child = server.spawn_with(shell("echo $COLUMNS $LINES"), options).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"100 30\n");
+    }
+
+    #[test]
+    fn term() {
+        // No other test depends on the default
+        set_default_term("xterm-test");
+        let cases = vec![
+            (SpawnOptions::new(), &b"xterm-test\r\n"[..]),
+            (SpawnOptions::new().term("vt100"), &b"vt100\r\n"[..]),
+        ];
+        for (options, expected) in cases {
+            let mut server = TtyServer::new_default().unwrap();
+            let mut child = server.spawn_with(shell("echo $TERM"), options).unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(read_output(&server), expected);
+        }
+        // The command environment comes first
+        let mut server = TtyServer::new_default().unwrap();
+        let mut cmd = shell("echo $TERM");
+        cmd.env("TERM", "dumb");
+        let mut child = server.spawn_with(cmd, SpawnOptions::new().term("vt100")).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read_output(&server), b"dumb\r\n");
+    }
+
+    #[test]
+    fn keep_master_flags() {
+        let server = TtyServer::new_default().unwrap();
+        let fd = server.get_master().as_raw_fd();
+        let flags = get_flags(fd).unwrap();
+        set_flags(fd, flags | libc::O_APPEND).unwrap();
+        for &keep in &[true, false] {
+            let mut peer = TtyServer::new_default().unwrap();
+            let builder = TtyClientBuilder::new().keep_master_flags(keep);
+            let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+            // The client master shares the file status flags of the server one
+            assert_eq!(get_flags(fd).unwrap() & libc::O_APPEND != 0, keep);
+            drop(client);
+            assert_ne!(get_flags(fd).unwrap() & libc::O_APPEND, 0);
+        }
+    }
+
+    #[test]
+    fn spawn_detached() {
+        let server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn_detached(shell("sleep 10")).unwrap();
+        // The closed terminal hangs up its controlling process
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGHUP));
+    }
+
+    #[test]
+    fn foreground_change() {
+        let mut server = TtyServer::new_default().unwrap();
+        // Job control requires a controlling terminal
+        let mut child = server.spawn_with(shell("set -m; read l; sleep 1"), ctty()).unwrap();
+        let shell_pgrp = child.id() as pid_t;
+        assert_eq!(server.foreground_pgrp().unwrap(), shell_pgrp);
+        let err = server.wait_foreground_change(shell_pgrp, Duration::from_millis(100)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        server.get_master().write_all(b"\n").unwrap();
+        let job = server.wait_foreground_change(shell_pgrp, Duration::from_secs(5)).unwrap();
+        assert_ne!(job, shell_pgrp);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn restore_winsize() {
+        for &restore in &[true, false] {
+            let server = TtyServer::new_default().unwrap();
+            let peer = TtyServer::new_default().unwrap();
+            let ws = WinSize::from((100, 30));
+            set_winsize(peer.get_master(), &ws).unwrap();
+            let builder = TtyClientBuilder::new().restore_winsize(restore);
+            let client = server.new_client_with(builder, peer.open_slave().unwrap(), None).unwrap();
+            client.set_winsize_from_peer().unwrap();
+            assert_eq!(get_winsize(server.get_master()).unwrap(), ws);
+            drop(client);
+            let expected = if restore { WinSize::default() } else { ws };
+            assert_eq!(get_winsize(server.get_master()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn restore_action() {
+        for &(action, kept) in &[(SetAction::Now, true), (SetAction::Flush, false)] {
+            let server = TtyServer::new_default().unwrap();
+            let peer = TtyServer::new_default().unwrap();
+            // The peer input is not read by the client
+            let builder = TtyClientBuilder::new().restore_action(action).directions(false, true);
+            let client = server.new_client_with(builder, peer.open_slave().unwrap(), None).unwrap();
+            peer.get_master().write_all(b"typed\n").unwrap();
+            let slave = peer.slave().unwrap();
+            let pending = || {
+                let mut n: c_int = 0;
+                assert_eq!(unsafe { libc::ioctl(slave.as_raw_fd(), libc::FIONREAD, &mut n) }, 0);
+                n
+            };
+            assert!(wait_for(|| pending() == 6));
+            drop(client);
+            assert_eq!(pending() == 6, kept);
+        }
+    }
+
+    #[test]
+    fn output_chunks() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("echo one && sleep 0.1 && echo two")).unwrap();
+        let chunks: Vec<_> = server.output_chunks().map(|c| c.unwrap()).collect();
+        assert!(child.wait().unwrap().success());
+        assert!(chunks.iter().all(|c| !c.is_empty()));
+        assert_eq!(chunks.concat(), b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn exited_output() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("head -c 2000 /dev/zero | tr '\\0' a")).unwrap();
+        assert!(child.wait().unwrap().success());
+        // The process already exited when the client is bound
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        client.wait();
+        drop(client);
+        assert_eq!(read_output(&peer), vec![b'a'; 2000]);
+    }
+
+    #[test]
+    fn max_duration() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn_with(shell("sleep 10"), ctty()).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().max_duration(Duration::from_millis(100), true);
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        assert!(!client.expired());
+        client.wait();
+        assert!(client.expired());
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGHUP));
+    }
+
+    #[test]
+    fn end_reason() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 0.2")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert_eq!(client.end_reason(), None);
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        assert!(client.child_gone());
+        assert_eq!(client.end_reason(), Some(EndReason::ChildExit));
+
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().max_duration(Duration::from_millis(100), false);
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        client.wait();
+        assert_eq!(client.end_reason(), Some(EndReason::MaxDuration));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn peer_closed() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        // The end of the peer input
+        drop(input.writer);
+        client.wait();
+        assert!(client.take_error().is_none());
+        assert_eq!(client.end_reason(), Some(EndReason::PeerClosed));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // The reader of the peer output is gone
+        let mut child = server.spawn(shell("yes")).unwrap();
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        drop(output.reader);
+        client.wait();
+        assert!(client.take_error().is_none());
+        assert_eq!(client.end_reason(), Some(EndReason::PeerClosed));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn close_peer_output() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("echo hi")).unwrap();
+        let input = new_pipe(None).unwrap();
+        let output = new_pipe(None).unwrap();
+        let master = server.get_master().try_clone().unwrap();
+        let _client = TtyClient::new_split(master, input.reader, output.writer, None).unwrap();
+        let (tx, rx) = channel();
+        let mut reader = output.reader;
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            let _ = tx.send(out);
+        });
+        // The end of file comes before the client is dropped
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"hi\r\n");
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn with_slave_termios() {
+        let server = TtyServer::new_default().unwrap();
+        let echo = || Termios::from_fd(server.get_master().as_raw_fd()).unwrap().c_lflag & termios::ECHO != 0;
+        let disable_echo = |t: &mut Termios| t.c_lflag &= !termios::ECHO;
+        assert!(!server.with_slave_termios(disable_echo, echo).unwrap());
+        assert!(echo());
+        // Also restored on panic
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            server.with_slave_termios(disable_echo, || panic!("run")).unwrap();
+        }));
+        assert!(result.is_err());
+        assert!(echo());
+    }
+
+    #[test]
+    fn wait_any() {
+        let mut servers = [TtyServer::new_default().unwrap(), TtyServer::new_default().unwrap()];
+        let mut long = servers[0].spawn(shell("sleep 10")).unwrap();
+        let mut short = servers[1].spawn(shell("sleep 0.2")).unwrap();
+        let mut peers = [TtyServer::new_default().unwrap(), TtyServer::new_default().unwrap()];
+        let clients: Vec<_> = servers.iter_mut().zip(peers.iter_mut())
+            .map(|(s, p)| s.new_client(p.take_slave().unwrap(), None).unwrap()).collect();
+        assert_eq!(super::wait_any(&[&clients[0], &clients[1]]), 1);
+        long.kill().unwrap();
+        long.wait().unwrap();
+        short.wait().unwrap();
+    }
+
+    #[test]
+    fn set_master_nonblocking() {
+        let mut server = TtyServer::new_default().unwrap();
+        server.set_master_nonblocking(true).unwrap();
+        let mut buf = [0; 1];
+        let err = server.get_master().read(&mut buf).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        server.set_master_nonblocking(false).unwrap();
+        assert_eq!(get_flags(server.get_master().as_raw_fd()).unwrap() & libc::O_NONBLOCK, 0);
+
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(server.set_master_nonblocking(true).is_err());
+        drop(client);
+        server.set_master_nonblocking(true).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn try_wait() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 0.2")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(!client.try_wait());
+        assert!(child.wait().unwrap().success());
+        assert!(wait_for(|| client.try_wait()));
+    }
+
+    #[test]
+    fn set_output_processing() {
+        let server = TtyServer::new_default().unwrap();
+        let mut slave = server.slave().unwrap();
+        let mut master = server.get_master();
+        let mut buf = [0; 4];
+        server.set_output_processing(OutputProcessing::new()).unwrap();
+        slave.write_all(b"hi\n").unwrap();
+        master.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"hi\n");
+        server.set_output_processing(OutputProcessing::new().onlcr(true)).unwrap();
+        slave.write_all(b"hi\n").unwrap();
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi\r\n");
+        let err = server.set_output_processing(OutputProcessing::new().cr_delay(4)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("yes")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        // The peer never reads its output
+        let builder = TtyClientBuilder::new().watchdog(Duration::from_millis(200));
+        let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        client.wait();
+        assert_eq!(client.end_reason(), Some(EndReason::Error));
+        assert_eq!(client.take_error().unwrap().kind(), io::ErrorKind::TimedOut);
+        // Hang up the peer to unblock the pending write
+        drop(peer);
+        drop(client);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn session_id() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        assert!(server.id() != peer.id());
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert!(client.id() != server.id() && client.id() != peer.id());
+        drop(client);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // A reused TTY is a new session
+        let mut pool = TtyServerPool::new(1);
+        let server = pool.acquire().unwrap();
+        let id = server.id();
+        pool.release(server).ok().unwrap();
+        assert!(pool.acquire().unwrap().id() != id);
+    }
+
+    #[test]
+    fn reset_peer_display() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        client.reset_peer_display().unwrap();
+        assert!(read_until(peer.get_master().try_clone().unwrap(), RESET_DISPLAY));
+        drop(client);
+
+        let builder = TtyClientBuilder::new().reset_display_on_drop(true);
+        let client = server.new_client_with(builder, peer.open_slave().unwrap(), None).unwrap();
+        drop(client);
+        assert!(read_until(peer.get_master().try_clone().unwrap(), RESET_DISPLAY));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn nonblocking_peer() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let mut peer = TtyServer::new_raw(None).unwrap();
+        let peer_slave = peer.take_slave().unwrap();
+        set_flags(peer_slave.as_raw_fd(), get_flags(peer_slave.as_raw_fd()).unwrap() | libc::O_NONBLOCK)
+            .unwrap();
+        let _client = server.new_client(peer_slave, None).unwrap();
+        // Enough to fill the peer output more than once
+        let mut child = server.spawn(shell("seq 1 20000; echo end")).unwrap();
+        let mut expected: Vec<u8> = (1..20001).map(|i| format!("{}\n", i)).collect::<String>().into();
+        expected.extend_from_slice(b"end\n");
+        let mut reader = peer.get_master().try_clone().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(n) = reader.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+                if out.ends_with(b"end\n") {
+                    let _ = tx.send(out);
+                    return;
+                }
+            }
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(10)).unwrap() == expected);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn child_gone() {
+        // Both the splice(2) and the read(2) proxies
+        for builder in [TtyClientBuilder::new(), TtyClientBuilder::new().buffer_pool(BufferPool::new(2, 16))] {
+            let mut server = TtyServer::new_default().unwrap();
+            let mut child = server.spawn(shell("exec >&- 2>&-; read l; test \"$l\" = hi")).unwrap();
+            let mut peer = TtyServer::new_default().unwrap();
+            let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+            // The remaining stdin keeps the binding
+            thread::sleep(Duration::from_millis(100));
+            assert!(!client.try_wait());
+            peer.get_master().write_all(b"hi\n").unwrap();
+            assert!(child.wait().unwrap().success());
+            client.wait();
+            assert!(client.child_gone());
+            assert!(client.take_error().is_none());
+        }
+    }
+
+    #[test]
+    fn drop_shared_peer() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("sleep 10")).unwrap();
+        let peer = TtyServer::new_default().unwrap();
+        // Same as the real stdin, also held by the parent shell
+        let peer_slave = peer.slave().unwrap().try_clone().unwrap();
+        let client = server.new_client(peer_slave, None).unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            drop(client);
+            let _ = tx.send(());
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn flow_control_xoff() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("while :; do echo x; sleep 0.02; done")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().flow_control(FlowControl::Slave);
+        let _client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+        let mut viewer = peer.get_master().try_clone().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n) = viewer.read(&mut buf) {
+                if n == 0 || tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        // Ctrl-S stops the output
+        peer.get_master().write_all(b"\x13").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        while rx.try_recv().is_ok() {}
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+        // Ctrl-Q resumes it
+        peer.get_master().write_all(b"\x11").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn owned_fds() {
+        let is_open = |fd: RawFd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+        let mut server = TtyServer::new_default().unwrap();
+        let slave = server.take_slave_owned().unwrap();
+        assert!(server.take_slave_owned().is_none());
+        let master = server.into_master_owned();
+        // Neither is closed by the server
+        assert!(is_open(slave.as_raw_fd()) && is_open(master.as_raw_fd()));
+        assert!(is_tty(slave.as_raw_fd()) && is_tty(master.as_raw_fd()));
+        assert!(!is_hangup(&master));
+        drop(slave);
+        assert!(is_hangup(&master));
+
+        // The slave not taken is closed with the server
+        let master = TtyServer::new_default().unwrap().into_master_owned();
+        assert!(is_open(master.as_raw_fd()));
+        assert!(is_hangup(&master));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn stats_latency() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("read l; echo \"got $l\"")).unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let start = Instant::now();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        assert_eq!(client.stats().samples(), 0);
+        assert!(client.stats().avg_forward_latency().is_none());
+        peer.get_master().write_all(b"hi\n").unwrap();
+        assert!(read_until(peer.get_master().try_clone().unwrap(), b"got hi"));
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        let session = start.elapsed();
+        // At least the first forward of each direction is measured
+        let stats = client.stats();
+        assert!(stats.samples() >= 2);
+        let latency = stats.avg_forward_latency().unwrap();
+        assert!(latency > Duration::new(0, 0) && latency <= session);
+    }
+
+    #[test]
+    fn attach_fds() {
+        let input = Pipe::new().unwrap();
+        let output = Pipe::new().unwrap();
+        let (server, client, mut child) = super::attach_fds(shell("read l; echo \"got $l\""), None::<&File>,
+            input.reader.as_raw_fd(), output.writer.as_raw_fd(), None).unwrap();
+        (&input.writer).write_all(b"hi\n").unwrap();
+        assert!(read_until(output.reader.try_clone().unwrap(), b"got hi"));
+        assert!(child.wait().unwrap().success());
+        client.wait();
+        assert!(client.child_gone());
+        drop(client);
+        drop(server);
+        // The peer file descriptors stay open
+        (&output.writer).write_all(b"end").unwrap();
+        assert!(read_until(output.reader.try_clone().unwrap(), b"end"));
+        (&input.writer).write_all(b"end").unwrap();
+        let mut buf = [0; 3];
+        (&input.reader).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"end");
+    }
+
+    #[test]
+    fn default_size() {
+        for &(standard, expected) in &[(true, &b"24 80\r\n"[..]), (false, &b"0 0\r\n"[..])] {
+            let mut server = if standard {
+                TtyServer::new_default().unwrap()
+            } else {
+                TtyServer::new(None::<&File>).unwrap()
+            };
+            let mut child = server.spawn(shell("stty size")).unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(read_output(&server), expected);
+        }
+    }
+
+    #[test]
+    fn directions() {
+        for &(input, output) in &[(true, false), (false, true), (true, true)] {
+            let mut server = TtyServer::new_raw(None).unwrap();
+            let mut slave = server.take_slave().unwrap();
+            let mut peer = TtyServer::new_default().unwrap();
+            let builder = TtyClientBuilder::new().directions(input, output);
+            let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+            let mut buf = [0; 16];
+
+            slave.write_all(b"out").unwrap();
+            assert_eq!(poll_one(peer.get_master().as_raw_fd(), libc::POLLIN, 500), output);
+            if output {
+                let n = peer.get_master().read(&mut buf).unwrap();
+                assert_eq!(&buf[..n], b"out");
+            }
+
+            peer.get_master().write_all(b"in\n").unwrap();
+            assert_eq!(poll_one(slave.as_raw_fd(), libc::POLLIN, 500), input);
+            if input {
+                let n = slave.read(&mut buf).unwrap();
+                assert_eq!(&buf[..n], b"in\n");
+            }
+            drop(client);
+        }
+        let server = TtyServer::new_default().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let builder = TtyClientBuilder::new().directions(false, false);
+        let err = server.new_client_with(builder, peer.take_slave().unwrap(), None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn hangup_on_kill() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut child = server.spawn(shell("exec sleep 10")).unwrap();
+        assert!(!server.slave_hangup_detected());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        // The only process using the slave is gone
+        assert!(server.slave_hangup_detected());
+    }
+
+    #[test]
+    #[should_panic(expected = "No client to wait for")]
+    fn wait_any_empty() {
+        super::wait_any(&[]);
     }
 }
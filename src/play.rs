@@ -0,0 +1,231 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::char;
+use std::io::{self, BufRead, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+/// Replay the output of a session recorded in the asciicast v2 format (cf. asciinema)
+///
+/// To display it through a `TtyClient`, the output must be written to the slave side of its TTY
+/// (e.g. `TtyServer::take_slave_handle()`).
+pub struct Player<R> {
+    reader: R,
+    speed: f64,
+}
+
+impl<R> Player<R> where R: BufRead {
+    pub fn new(reader: R) -> Player<R> {
+        Player {
+            reader,
+            speed: 1.0,
+        }
+    }
+
+    /// Multiply the replay speed by `speed` (e.g. 2.0 is twice as fast)
+    pub fn speed(mut self, speed: f64) -> Player<R> {
+        self.speed = speed;
+        self
+    }
+
+    /// Write the output events to `out` according to their recorded timing
+    ///
+    /// The other events (e.g. input or resize) are ignored.
+    pub fn play<W>(self, out: &mut W) -> io::Result<()> where W: Write {
+        if self.speed.is_nan() || self.speed <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid replay speed"));
+        }
+        let start = Instant::now();
+        let mut lines = self.reader.lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header.trim_start().starts_with('{') => {},
+            Some(Err(e)) => return Err(e),
+            Some(Ok(_)) => return Err(invalid("Missing asciicast header")),
+            None => return Ok(()),
+        }
+        for line in lines {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, kind, data) = try!(parse_event(&line));
+            if kind != "o" {
+                continue;
+            }
+            let due = time.max(0.0) / self.speed;
+            // A tiny speed may make a valid time overflow
+            if due >= u64::MAX as f64 {
+                return Err(invalid("Event time out of range"));
+            }
+            let due = Duration::from_secs_f64(due);
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+            try!(out.write_all(data.as_bytes()));
+            try!(out.flush());
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Parse an event line: [time, "type", "data"]
+fn parse_event(line: &str) -> io::Result<(f64, String, String)> {
+    let mut input = line.trim().chars().peekable();
+    try!(expect_char(&mut input, '['));
+    let mut time = String::new();
+    while let Some(&c) = input.peek() {
+        if c == ',' {
+            break;
+        }
+        time.push(c);
+        input.next();
+    }
+    let time = match time.trim().parse::<f64>() {
+        // Also reject NaN and an out of range time (e.g. 1e400)
+        Ok(t) if t.is_finite() => t,
+        _ => return Err(invalid("Invalid event time")),
+    };
+    try!(expect_char(&mut input, ','));
+    let kind = try!(parse_string(&mut input));
+    try!(expect_char(&mut input, ','));
+    let data = try!(parse_string(&mut input));
+    try!(expect_char(&mut input, ']'));
+    Ok((time, kind, data))
+}
+
+fn expect_char(input: &mut Input, expected: char) -> io::Result<()> {
+    while input.peek().map_or(false, |c| c.is_whitespace()) {
+        input.next();
+    }
+    match input.next() {
+        Some(c) if c == expected => Ok(()),
+        _ => Err(invalid("Invalid event")),
+    }
+}
+
+// Parse a JSON string
+fn parse_string(input: &mut Input) -> io::Result<String> {
+    try!(expect_char(input, '"'));
+    let mut s = String::new();
+    loop {
+        match input.next() {
+            None => return Err(invalid("Unterminated string")),
+            Some('"') => return Ok(s),
+            Some('\\') => match input.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('/') => s.push('/'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let mut code = try!(parse_hex4(input));
+                    // UTF-16 surrogate pair
+                    if (0xd800..0xdc00).contains(&code) {
+                        try!(expect_char(input, '\\'));
+                        try!(expect_char(input, 'u'));
+                        let low = try!(parse_hex4(input));
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                },
+                _ => return Err(invalid("Invalid string escape")),
+            },
+            Some(c) => s.push(c),
+        }
+    }
+}
+
+fn parse_hex4(input: &mut Input) -> io::Result<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        match input.next().and_then(|c| c.to_digit(16)) {
+            Some(d) => code = code * 16 + d,
+            None => return Err(invalid("Invalid unicode escape")),
+        }
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &str = "{\"version\": 2, \"width\": 80, \"height\": 24}\n";
+
+    fn play(cast: &str, speed: f64) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        try!(Player::new(Cursor::new(cast.as_bytes())).speed(speed).play(&mut out));
+        Ok(out)
+    }
+
+    #[test]
+    fn output_events() {
+        let cast = format!("{}[0.0, \"o\", \"a\\u00e9\"]\n[0.01, \"i\", \"x\"]\n\n[0.02, \"o\", \"\\r\\n\"]\n", HEADER);
+        assert_eq!(play(&cast, 1.0).unwrap(), "a\u{e9}\r\n".as_bytes());
+    }
+
+    #[test]
+    fn speed_up() {
+        let cast = format!("{}[10.0, \"o\", \"late\"]\n", HEADER);
+        let start = Instant::now();
+        assert_eq!(play(&cast, 1000.0).unwrap(), b"late");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn surrogate_pair() {
+        let cast = format!("{}[0, \"o\", \"\\ud83d\\ude00\"]\n", HEADER);
+        assert_eq!(play(&cast, 1.0).unwrap(), "\u{1f600}".as_bytes());
+    }
+
+    #[test]
+    fn invalid_time() {
+        for time in &["1e400", "inf", "NaN", "x"] {
+            let cast = format!("{}[{}, \"o\", \"a\"]\n", HEADER, time);
+            assert_eq!(play(&cast, 1.0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn time_out_of_range() {
+        let cast = format!("{}[1e300, \"o\", \"a\"]\n", HEADER);
+        assert_eq!(play(&cast, 1e-300).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_speed() {
+        assert_eq!(play(HEADER, 0.0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(play(HEADER, -1.0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(play("[0, \"o\", \"a\"]\n", 1.0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(play("", 1.0).unwrap().is_empty());
+    }
+}
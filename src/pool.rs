@@ -0,0 +1,221 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use termios::Termios;
+use TtyServer;
+
+/// Cache of TTYs to reuse them for short-lived sessions
+pub struct TtyServerPool {
+    servers: Vec<TtyServer>,
+    max: usize,
+    // Configuration of a new TTY, restored on release
+    termios: Option<Termios>,
+}
+
+impl TtyServerPool {
+    /// Keep at most `max` unused TTYs
+    pub fn new(max: usize) -> TtyServerPool {
+        TtyServerPool {
+            servers: Vec::new(),
+            max,
+            termios: None,
+        }
+    }
+
+    /// Get an unused TTY, or create a new one (cf. `TtyServer::new()`)
+    pub fn acquire(&mut self) -> io::Result<TtyServer> {
+        if let Some(server) = self.servers.pop() {
+            return Ok(server);
+        }
+        let server = try!(TtyServer::new_default());
+        if self.termios.is_none() {
+            self.termios = Some(try!(Termios::from_fd(server.master.as_raw_fd())));
+        }
+        Ok(server)
+    }
+
+    /// Give back a TTY to be reused by a next `acquire()`
+    ///
+    /// The slave is reopened if needed, the pending data is discarded, the configuration
+    /// (termios and size) of a new TTY is restored, and the server gets a new session identifier.
+    /// The TTY is closed instead if the pool is full or if it can't be reset.
+    ///
+    /// The server is given back as an error if a client is still bound to it or if a process
+    /// still uses its slave (e.g. spawned with `spawn_keep_slave()`).
+    pub fn release(&mut self, mut server: TtyServer) -> Result<(), TtyServer> {
+        if server.is_attached() {
+            return Err(server);
+        }
+        if self.servers.len() >= self.max {
+            return Ok(());
+        }
+        if let Some(termios) = self.termios {
+            if server.reset(&termios).is_ok() {
+                self.servers.push(server);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of unused TTYs
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Check if there is no unused TTY
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+/// Cache of buffers for the read/write proxy (cf. `TtyClientBuilder::buffer_pool()`)
+///
+/// The buffers are allocated once and shared by all the clones of the pool, e.g. to reuse them
+/// across short-lived sessions instead of allocating new ones for each client.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max: usize,
+    size: usize,
+}
+
+impl BufferPool {
+    /// Allocate `max` buffers of `size` bytes
+    pub fn new(max: usize, size: usize) -> BufferPool {
+        BufferPool {
+            buffers: Arc::new(Mutex::new((0..max).map(|_| vec![0; size]).collect())),
+            max,
+            size,
+        }
+    }
+
+    /// Get an unused buffer, or allocate a new one if they are all in use
+    pub fn acquire(&self) -> Vec<u8> {
+        let buffer = match self.buffers.lock() {
+            Ok(mut b) => b.pop(),
+            Err(e) => e.into_inner().pop(),
+        };
+        buffer.unwrap_or_else(|| vec![0; self.size])
+    }
+
+    /// Give back a buffer to be reused by a next `acquire()`
+    ///
+    /// The buffer is dropped instead if the pool is full or if its size is not the pool one.
+    pub fn release(&self, buffer: Vec<u8>) {
+        if buffer.len() != self.size {
+            return;
+        }
+        let mut buffers = match self.buffers.lock() {
+            Ok(b) => b,
+            Err(e) => e.into_inner(),
+        };
+        if buffers.len() < self.max {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Size of the buffers
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of unused buffers
+    pub fn len(&self) -> usize {
+        match self.buffers.lock() {
+            Ok(b) => b.len(),
+            Err(e) => e.into_inner().len(),
+        }
+    }
+
+    /// Check if all the buffers are in use
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use {SpawnOptions, TtyServer};
+
+    #[test]
+    fn reuse() {
+        let mut pool = TtyServerPool::new(1);
+        assert!(pool.is_empty());
+        let server = pool.acquire().unwrap();
+        let id = server.id();
+        assert!(pool.release(server).is_ok());
+        assert_eq!(pool.len(), 1);
+        let server = pool.acquire().unwrap();
+        assert!(pool.is_empty());
+        assert!(server.id() != id);
+        assert!(server.slave().is_some());
+    }
+
+    #[test]
+    fn full() {
+        let mut pool = TtyServerPool::new(1);
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        assert!(pool.release(first).is_ok());
+        assert!(pool.release(second).is_ok());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn refuse_bound_client() {
+        let mut pool = TtyServerPool::new(1);
+        let server = pool.acquire().unwrap();
+        let mut peer = TtyServer::new_default().unwrap();
+        let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+        let server = pool.release(server).unwrap_err();
+        drop(client);
+        assert!(pool.release(server).is_ok());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn refuse_kept_slave() {
+        let mut pool = TtyServerPool::new(1);
+        let mut server = pool.acquire().unwrap();
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+        let mut child = server.spawn_keep_slave(cmd, SpawnOptions::new()).unwrap();
+        let server = pool.release(server).unwrap_err();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(pool.release(server).is_ok());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn buffers() {
+        let pool = BufferPool::new(1, 16);
+        let buffer = pool.acquire();
+        assert_eq!(buffer.len(), 16);
+        assert!(pool.is_empty());
+        // Allocated when all the buffers are in use
+        let other = pool.clone().acquire();
+        pool.release(buffer);
+        pool.release(other);
+        assert_eq!(pool.len(), 1);
+        pool.release(vec![0; 8]);
+        assert_eq!(pool.len(), 1);
+    }
+
+}
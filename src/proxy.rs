@@ -0,0 +1,663 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use fault::{self, Syscall};
+use fd::Pipe;
+use libc::{self, c_int, c_short};
+use pool::BufferPool;
+use std::cmp;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Default pipe capacity
+const SPLICE_BUFFER_SIZE: usize = 64 * 1024;
+
+// Interval between two checks of an aborted flush
+const ABORT_POLL_MS: c_int = 100;
+
+// Only measure one forward out of this number
+#[cfg(feature = "metrics")]
+const LATENCY_SAMPLE_RATE: usize = 16;
+
+/// Proxy statistics
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug)]
+pub struct TtyStats {
+    samples: u32,
+    latency: Duration,
+}
+
+#[cfg(feature = "metrics")]
+impl TtyStats {
+    /// Number of forwards measured so far
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Average time between some data being readable and its forward completion
+    pub fn avg_forward_latency(&self) -> Option<Duration> {
+        self.latency.checked_div(self.samples)
+    }
+}
+
+/// State shared by the proxy threads of a client
+#[derive(Clone)]
+pub struct Proxy {
+    pub do_flush: Arc<AtomicBool>,
+    // Wake up the threads blocked on an idle file descriptor
+    stop: Arc<Pipe>,
+    flush_event: Sender<()>,
+    // First error which stopped a thread
+    error: Arc<Mutex<Option<io::Error>>>,
+    // Still set once the error is taken
+    failed: Arc<AtomicBool>,
+    // Skip the flush of the remaining data
+    abort: Arc<AtomicBool>,
+    // Number of forwards, to detect a stuck proxy
+    progress: Arc<AtomicUsize>,
+    #[cfg(feature = "metrics")]
+    pub stats: Arc<Mutex<TtyStats>>,
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    try!(fault::check(Syscall::Splice));
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    match unsafe { libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len, flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+fn read_fd(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+fn write_fd(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    match unsafe { libc::write(fd, buf.as_ptr() as *const _, buf.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+// Duplicate the data of the pipe `fd_in` into the pipe `fd_out` without consuming it
+fn tee(fd_in: RawFd, fd_out: RawFd) -> io::Result<usize> {
+    match unsafe { libc::tee(fd_in, fd_out, SPLICE_BUFFER_SIZE, libc::SPLICE_F_NONBLOCK) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+enum Wakeup {
+    Ready,
+    // The stop pipe woke us up
+    Stop,
+    // The other end is closed and there is nothing left to read
+    HangUp,
+}
+
+fn wait_for(fd: RawFd, events: c_short, stop: &Pipe) -> io::Result<Wakeup> {
+    let mut fds = [
+        libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stop.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // These events are always reported and would never be cleared by a read or a write
+    let revents = fds[0].revents;
+    if revents & libc::POLLNVAL != 0 {
+        Err(io::Error::from_raw_os_error(libc::EBADF))
+    } else if revents & libc::POLLERR != 0 {
+        Err(io::Error::new(io::ErrorKind::Other, "Error condition on a proxied file descriptor"))
+    } else if revents & events != 0 {
+        Ok(Wakeup::Ready)
+    } else if revents & libc::POLLHUP != 0 {
+        Ok(Wakeup::HangUp)
+    } else {
+        Ok(Wakeup::Stop)
+    }
+}
+
+// Maximum size of a pipe for an unprivileged user
+const PIPE_MAX_SIZE_PATH: &str = "/proc/sys/fs/pipe-max-size";
+
+/// Create a pipe which can hold `size` bytes, up to the system limit, if any
+pub fn new_pipe(size: Option<usize>) -> io::Result<Pipe> {
+    let pipe = match Pipe::new() {
+        Ok(p) => p,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+    if let Some(size) = size {
+        let max = match fs::read_to_string(PIPE_MAX_SIZE_PATH) {
+            Ok(m) => m.trim().parse().unwrap_or(size),
+            Err(_) => size,
+        };
+        let size = cmp::min(cmp::min(size, max), c_int::MAX as usize);
+        if unsafe { libc::fcntl(pipe.writer.as_raw_fd(), libc::F_SETPIPE_SZ, size as c_int) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(pipe)
+}
+
+pub fn poll_one(fd: RawFd, events: c_short, timeout: c_int) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, timeout) == 1 }
+}
+
+// Ignore the hang up and error conditions to not loop on them
+fn is_readable(fd: RawFd) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) == 1 && pfd.revents & libc::POLLIN != 0 }
+}
+
+// Number of bytes which can be read without blocking
+fn pending_len(fd: RawFd) -> usize {
+    if !is_readable(fd) {
+        return 0;
+    }
+    let mut len: c_int = 0;
+    match unsafe { libc::ioctl(fd, libc::FIONREAD, &mut len) } {
+        -1 => 0,
+        _ => len as usize,
+    }
+}
+
+impl Proxy {
+    /// Notify `flush_event` each time a proxy thread stops
+    pub fn new(flush_event: Sender<()>) -> io::Result<Proxy> {
+        let stop = match Pipe::new() {
+            Ok(p) => p,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+        Ok(Proxy {
+            do_flush: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(stop),
+            flush_event,
+            error: Arc::new(Mutex::new(None)),
+            failed: Arc::new(AtomicBool::new(false)),
+            abort: Arc::new(AtomicBool::new(false)),
+            progress: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "metrics")]
+            stats: Arc::new(Mutex::new(TtyStats {
+                samples: 0,
+                latency: Duration::new(0, 0),
+            })),
+        })
+    }
+
+    /// Ask all the proxy threads to flush
+    pub fn request_flush(&self) {
+        if !self.do_flush.swap(true, Relaxed) {
+            // The pipe is never read to stay readable for every thread
+            let buf = [0u8];
+            let _ = unsafe { libc::write(self.stop.writer.as_raw_fd(), buf.as_ptr() as *const _, 1) };
+        }
+    }
+
+    /// Wait until a flush is requested or `timeout` elapsed, and return true in the first case
+    pub fn wait_flush_request(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.do_flush.load(Relaxed) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            let remaining = deadline - now;
+            // Round up to not spin during the last millisecond
+            let ms = remaining.as_secs() * 1000 + (remaining.subsec_nanos() as u64 + 999_999) / 1_000_000;
+            poll_one(self.stop.reader.as_raw_fd(), libc::POLLIN, cmp::min(ms, c_int::MAX as u64) as c_int);
+        }
+    }
+
+    /// Get the error which stopped the proxy, if any
+    pub fn take_error(&self) -> Option<io::Error> {
+        match self.error.lock() {
+            Ok(mut error) => error.take(),
+            Err(_) => None,
+        }
+    }
+
+    /// Get a file descriptor which becomes readable once a flush is requested
+    pub fn flush_request_fd(&self) -> RawFd {
+        self.stop.reader.as_raw_fd()
+    }
+
+    /// Check if an error stopped the proxy, even if already taken
+    pub fn failed(&self) -> bool {
+        self.failed.load(Relaxed)
+    }
+
+    // Stop with `err` without waiting for the remaining data to be forwarded
+    fn abort(&self, err: io::Error) {
+        self.set_error(err);
+        self.abort.store(true, Relaxed);
+        self.request_flush();
+    }
+
+    // Wait for `events` on `fd` during a flush, unless it is aborted
+    fn wait_flushing(&self, fd: RawFd, events: c_short) -> bool {
+        while !self.abort.load(Relaxed) {
+            if poll_one(fd, events, ABORT_POLL_MS) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Only keep the first error, which is the cause of the others
+    fn set_error(&self, err: io::Error) {
+        self.failed.store(true, Relaxed);
+        if let Ok(mut error) = self.error.lock() {
+            if error.is_none() {
+                *error = Some(err);
+            }
+        }
+    }
+
+    // Record the errors, which stop the loop as a flush request
+    fn wait_ready(&self, fd: RawFd, events: c_short) -> Wakeup {
+        loop {
+            match wait_for(fd, events, &self.stop) {
+                Ok(r) => return r,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // Closed by another part of the program, which ends the forwarding as a hang up
+                Err(ref e) if e.raw_os_error() == Some(libc::EBADF) => return Wakeup::HangUp,
+                Err(e) => {
+                    self.set_error(e);
+                    return Wakeup::Stop;
+                },
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_latency(&self, latency: Duration) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.samples += 1;
+            stats.latency += latency;
+        }
+    }
+
+    /// Forward data from `fd_in` to `fd_out` until an end of file, an error or a flush request
+    ///
+    /// One of `fd_in` or `fd_out` must be a pipe. If a `tap` pipe is given, `fd_in` must be a
+    /// pipe too and all the forwarded data is copied to `tap`. Once stopped, the remaining
+    /// available data is forwarded, a flush is requested to the other threads and an event is
+    /// sent to `flush_event`.
+    ///
+    /// If `fd_in` is a pipe fed by another thread of the proxy, `from_thread` makes the flush
+    /// wait for this other thread to close its end, so that all its remaining data is forwarded.
+    ///
+    /// The `eof` flag, if any, is set if stopped by the end of `fd_in` (e.g. all the slave file
+    /// descriptors of a master are closed), as opposed to an error or a flush request. This is
+    /// done before the flush request, i.e. before `TtyClient::wait()` returns.
+    pub fn splice_loop(&self, fd_in: RawFd, fd_out: RawFd, tap: Option<RawFd>, from_thread: bool,
+                       eof_flag: Option<&AtomicBool>) {
+        // Bytes copied to the tap but not forwarded yet
+        let mut teed = 0;
+        let mut eof = false;
+        #[cfg(feature = "metrics")]
+        let mut forwards: usize = 0;
+        while !self.do_flush.load(Relaxed) {
+            match self.wait_ready(fd_in, libc::POLLIN) {
+                Wakeup::Ready => {},
+                Wakeup::HangUp => {
+                    eof = true;
+                    break;
+                },
+                Wakeup::Stop => break,
+            }
+            #[cfg(feature = "metrics")]
+            let ready = Instant::now();
+            if let Some(tap) = tap {
+                if teed == 0 {
+                    match tee(fd_in, tap) {
+                        Ok(0) => {
+                            eof = true;
+                            break;
+                        },
+                        Ok(n) => teed = n,
+                        // The tap pipe is full
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            match self.wait_ready(tap, libc::POLLOUT) {
+                                Wakeup::Ready => continue,
+                                _ => break,
+                            }
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            self.set_error(e);
+                            break;
+                        },
+                    }
+                }
+            }
+            let len = if tap.is_some() { teed } else { SPLICE_BUFFER_SIZE };
+            match splice(fd_in, fd_out, len) {
+                // End of file, which is not a would-block condition thanks to the poll
+                Ok(0) => {
+                    eof = true;
+                    break;
+                },
+                Ok(n) => {
+                    teed = teed.saturating_sub(n);
+                    self.progress.fetch_add(1, Relaxed);
+                    #[cfg(feature = "metrics")]
+                    {
+                        if forwards % LATENCY_SAMPLE_RATE == 0 {
+                            self.record_latency(ready.elapsed());
+                        }
+                        forwards = forwards.wrapping_add(1);
+                    }
+                },
+                // The output is full or, for a nonblocking input, there is nothing left to read
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    match self.wait_ready(fd_out, libc::POLLOUT) {
+                        Wakeup::Ready => {},
+                        _ => break,
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // A master returns EIO once all the slave file descriptors are closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => {
+                    eof = true;
+                    break;
+                },
+                // The output is a pipe or a socket closed by its reader (e.g. the peer)
+                Err(ref e) if e.raw_os_error() == Some(libc::EPIPE) => break,
+                Err(e) => {
+                    self.set_error(e);
+                    break;
+                },
+            }
+        }
+        if eof {
+            if let Some(flag) = eof_flag {
+                flag.store(true, Relaxed);
+            }
+        }
+        self.request_flush();
+
+        // Never block on an empty input, even a TTY shared with other readers (e.g. the real stdin
+        // of the process), which would prevent the client to be dropped until the next key press
+        while !self.abort.load(Relaxed) {
+            let mut pending = pending_len(fd_in);
+            if pending == 0 && from_thread {
+                // Woken up by new data or the other thread exiting
+                self.wait_flushing(fd_in, libc::POLLIN);
+                pending = pending_len(fd_in);
+            }
+            if pending == 0 {
+                break;
+            }
+            if let Some(tap) = tap {
+                if teed == 0 {
+                    match tee(fd_in, tap) {
+                        Ok(0) => break,
+                        Ok(n) => teed = n,
+                        // The recorder is still running
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            if !self.wait_flushing(tap, libc::POLLOUT) {
+                                break;
+                            }
+                            continue;
+                        },
+                        Err(_) => break,
+                    }
+                }
+            }
+            let len = if tap.is_some() { teed } else { cmp::min(pending, SPLICE_BUFFER_SIZE) };
+            match splice(fd_in, fd_out, len) {
+                Ok(0) => break,
+                Ok(n) => teed = teed.saturating_sub(n),
+                // A nonblocking output is full, which would otherwise block the splice
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.wait_flushing(fd_out, libc::POLLOUT) {
+                        break;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+        let _ = self.flush_event.send(());
+    }
+
+    /// Spawn a `splice_loop()` thread owning the `pipe` end, which is either `fd_in` or `fd_out`,
+    /// and the `tap` pipe writer if any
+    ///
+    /// The `eof` flag, if any, is the `splice_loop()` one.
+    pub fn spawn_splice_loop(&self, fd_in: RawFd, fd_out: RawFd, pipe: File, tap: Option<File>,
+                             eof: Option<Arc<AtomicBool>>) -> JoinHandle<()> {
+        let proxy = self.clone();
+        thread::spawn(move || {
+            let from_thread = pipe.as_raw_fd() == fd_in;
+            proxy.splice_loop(fd_in, fd_out, tap.as_ref().map(|t| t.as_raw_fd()), from_thread,
+                              eof.as_deref());
+            // Close the pipe ends to notify the other threads
+            drop(pipe);
+            drop(tap);
+        })
+    }
+
+    /// Same as `splice_loop()` but forward the data with `read(2)` and `write(2)` through `buf`
+    ///
+    /// Neither `fd_in` nor `fd_out` needs to be a pipe. If a `tap` is given, all the forwarded
+    /// data is also written to it.
+    pub fn copy_loop(&self, fd_in: RawFd, fd_out: RawFd, buf: &mut [u8], tap: Option<RawFd>,
+                     eof_flag: Option<&AtomicBool>) {
+        let mut eof = false;
+        #[cfg(feature = "metrics")]
+        let mut forwards: usize = 0;
+        while !self.do_flush.load(Relaxed) {
+            match self.wait_ready(fd_in, libc::POLLIN) {
+                Wakeup::Ready => {},
+                Wakeup::HangUp => {
+                    eof = true;
+                    break;
+                },
+                Wakeup::Stop => break,
+            }
+            #[cfg(feature = "metrics")]
+            let ready = Instant::now();
+            match read_fd(fd_in, buf) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                },
+                Ok(n) => match self.write_out(&buf[..n], fd_out, tap) {
+                    Ok(()) => {
+                        self.progress.fetch_add(1, Relaxed);
+                        #[cfg(feature = "metrics")]
+                        {
+                            if forwards % LATENCY_SAMPLE_RATE == 0 {
+                                self.record_latency(ready.elapsed());
+                            }
+                            forwards = forwards.wrapping_add(1);
+                        }
+                    },
+                    // The output is a pipe or a socket closed by its reader (e.g. the peer)
+                    Err(ref e) if e.raw_os_error() == Some(libc::EPIPE) => break,
+                    Err(e) => {
+                        self.set_error(e);
+                        break;
+                    },
+                },
+                // A nonblocking input may be drained by another reader in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // A master returns EIO once all the slave file descriptors are closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => {
+                    eof = true;
+                    break;
+                },
+                Err(e) => {
+                    self.set_error(e);
+                    break;
+                },
+            }
+        }
+        if eof {
+            if let Some(flag) = eof_flag {
+                flag.store(true, Relaxed);
+            }
+        }
+        self.request_flush();
+
+        // Same as the splice_loop() flush, without blocking on an empty input
+        while !self.abort.load(Relaxed) {
+            let pending = cmp::min(pending_len(fd_in), buf.len());
+            if pending == 0 {
+                break;
+            }
+            match read_fd(fd_in, &mut buf[..pending]) {
+                Ok(0) => break,
+                Ok(n) => if self.write_out(&buf[..n], fd_out, tap).is_err() {
+                    break;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+        let _ = self.flush_event.send(());
+    }
+
+    // Write all `data` to `fd_out` then to `tap`, waiting for a full output unless aborted
+    fn write_out(&self, data: &[u8], fd_out: RawFd, tap: Option<RawFd>) -> io::Result<()> {
+        for &fd in Some(fd_out).iter().chain(tap.iter()) {
+            let mut data = data;
+            while !data.is_empty() {
+                match write_fd(fd, data) {
+                    Ok(n) => data = &data[n..],
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if !self.wait_flushing(fd, libc::POLLOUT) {
+                            return Err(io::Error::new(io::ErrorKind::Other, "The proxy was aborted"));
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawn a `copy_loop()` thread with a buffer from `pool`, given back once stopped, and
+    /// owning the `tap` pipe writer if any
+    ///
+    /// The `eof` flag, if any, is the `copy_loop()` one.
+    pub fn spawn_copy_loop(&self, fd_in: RawFd, fd_out: RawFd, pool: BufferPool, tap: Option<File>,
+                           eof: Option<Arc<AtomicBool>>) -> JoinHandle<()> {
+        let proxy = self.clone();
+        let mut buf = pool.acquire();
+        thread::spawn(move || {
+            proxy.copy_loop(fd_in, fd_out, &mut buf, tap.as_ref().map(|t| t.as_raw_fd()),
+                            eof.as_deref());
+            pool.release(buf);
+            drop(tap);
+        })
+    }
+
+    /// Spawn a thread stopping the proxy if nothing was forwarded during `interval` whereas
+    /// some data is waiting on one of the `inputs`
+    ///
+    /// The proxy is then stopped with a `TimedOut` error, without flushing the remaining data.
+    pub fn spawn_watchdog(&self, interval: Duration, inputs: Vec<RawFd>) -> JoinHandle<()> {
+        let proxy = self.clone();
+        thread::spawn(move || {
+            let mut last = proxy.progress.load(Relaxed);
+            while !proxy.wait_flush_request(interval) {
+                let progress = proxy.progress.load(Relaxed);
+                if progress == last && inputs.iter().any(|&fd| pending_len(fd) > 0) {
+                    proxy.abort(io::Error::new(io::ErrorKind::TimedOut, "The proxy is stuck"));
+                    break;
+                }
+                last = progress;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipe_size(pipe: &Pipe) -> usize {
+        unsafe { libc::fcntl(pipe.writer.as_raw_fd(), libc::F_GETPIPE_SZ) as usize }
+    }
+
+    #[test]
+    fn new_pipe_size() {
+        assert_eq!(pipe_size(&new_pipe(None).unwrap()), SPLICE_BUFFER_SIZE);
+        assert_eq!(pipe_size(&new_pipe(Some(256 * 1024)).unwrap()), 256 * 1024);
+        // Capped to the system limit
+        let max: usize = fs::read_to_string(PIPE_MAX_SIZE_PATH).unwrap().trim().parse().unwrap();
+        assert_eq!(pipe_size(&new_pipe(Some(usize::MAX)).unwrap()), max);
+    }
+
+    #[test]
+    fn wait_for_conditions() {
+        let stop = new_pipe(None).unwrap();
+        let wait = |fd: RawFd, events| match wait_for(fd, events, &stop) {
+            Ok(Wakeup::Ready) => "ready",
+            Ok(Wakeup::Stop) => "stop",
+            Ok(Wakeup::HangUp) => "hangup",
+            Err(ref e) if e.raw_os_error() == Some(libc::EBADF) => "invalid",
+            Err(_) => "error",
+        };
+        let pipe = new_pipe(None).unwrap();
+        assert_eq!(wait(pipe.writer.as_raw_fd(), libc::POLLOUT), "ready");
+        drop(pipe.writer);
+        assert_eq!(wait(pipe.reader.as_raw_fd(), libc::POLLIN), "hangup");
+        let pipe = new_pipe(None).unwrap();
+        drop(pipe.reader);
+        assert_eq!(wait(pipe.writer.as_raw_fd(), libc::POLLOUT), "error");
+        // Beyond any file descriptor limit
+        assert_eq!(wait(1 << 30, libc::POLLIN), "invalid");
+        let pipe = new_pipe(None).unwrap();
+        write_fd(stop.writer.as_raw_fd(), b"x").unwrap();
+        assert_eq!(wait(pipe.reader.as_raw_fd(), libc::POLLIN), "stop");
+    }
+}
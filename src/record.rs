@@ -0,0 +1,478 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use SessionId;
+use ffi::WinSize;
+#[cfg(feature = "flate2")]
+use flate2::Compression;
+#[cfg(feature = "flate2")]
+use flate2::write::GzEncoder;
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Behavior once a recording limit is reached
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnLimit {
+    /// Stop recording but keep the session running
+    Stop,
+    /// Only keep the last bytes, written at the end of the session
+    Truncate,
+}
+
+/// Compression format of a recording
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    /// Gzip stream with the default compression level
+    #[cfg(feature = "flate2")]
+    Gzip,
+    /// Zstandard stream with the default compression level
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Context of a recorded session, written as a JSON line before the recorded output
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionMeta {
+    command: Vec<String>,
+    timestamp: u64,
+    user: Option<String>,
+    env: Vec<(String, String)>,
+    size: Option<WinSize>,
+    session: Option<SessionId>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl SessionMeta {
+    /// Start time of now and user from `$USER`
+    pub fn new() -> SessionMeta {
+        SessionMeta {
+            command: Vec::new(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            user: env::var("USER").ok(),
+            env: Vec::new(),
+            size: None,
+            session: None,
+        }
+    }
+
+    /// Same as `new()` with the command line and the `TERM` of `cmd`
+    ///
+    /// The `TERM` of the current process is used if not set for `cmd`. The other environment
+    /// variables are not recorded unless explicitly added with `env()`.
+    pub fn from_command(cmd: &Command) -> SessionMeta {
+        let mut meta = SessionMeta::new();
+        meta.command.push(cmd.get_program().to_string_lossy().into_owned());
+        meta.command.extend(cmd.get_args().map(|a| a.to_string_lossy().into_owned()));
+        let term = cmd.get_envs()
+            .find(|&(k, _)| k == "TERM")
+            .map(|(_, v)| v.map(|v| v.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| env::var("TERM").ok());
+        if let Some(term) = term {
+            meta.env.push(("TERM".to_string(), term));
+        }
+        meta
+    }
+
+    /// Record the environment variable `name`
+    pub fn env(mut self, name: &str, value: &str) -> SessionMeta {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the terminal size, otherwise taken from the master when the client is bound
+    pub fn winsize(mut self, size: WinSize) -> SessionMeta {
+        self.size = Some(size);
+        self
+    }
+
+    /// Serialize as a JSON object, without new line
+    pub fn to_json(&self) -> String {
+        let command: Vec<String> = self.command.iter().map(|a| json_string(a)).collect();
+        let env: Vec<String> = self.env.iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect();
+        let mut json = format!("{{\"version\":1,\"timestamp\":{},\"command\":[{}]", self.timestamp,
+                               command.join(","));
+        if let Some(ref user) = self.user {
+            json.push_str(&format!(",\"user\":{}", json_string(user)));
+        }
+        json.push_str(&format!(",\"env\":{{{}}}", env.join(",")));
+        if let Some(size) = self.size {
+            json.push_str(&format!(",\"cols\":{},\"rows\":{}", size.cols(), size.rows()));
+        }
+        if let Some(session) = self.session {
+            json.push_str(&format!(",\"session\":{}", session));
+        }
+        json.push('}');
+        json
+    }
+}
+
+impl Default for SessionMeta {
+    fn default() -> SessionMeta {
+        SessionMeta::new()
+    }
+}
+
+// A writer which may need to be finalized (e.g. a compression stream)
+trait Sink: Write + Send {
+    fn finish(&mut self) -> io::Result<()>;
+
+    // Finalize and give back the underlying writer
+    fn into_writer(self: Box<Self>) -> io::Result<Box<Write + Send>>;
+}
+
+// Create a sink of the same kind for a new writer
+type NewSink = fn(Box<Write + Send>) -> io::Result<Box<Sink>>;
+
+struct Plain(Box<Write + Send>);
+
+impl Write for Plain {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Sink for Plain {
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn into_writer(mut self: Box<Self>) -> io::Result<Box<Write + Send>> {
+        try!(self.0.flush());
+        Ok(self.0)
+    }
+}
+
+fn plain_sink(writer: Box<Write + Send>) -> io::Result<Box<Sink>> {
+    Ok(Box::new(Plain(writer)))
+}
+
+#[cfg(feature = "flate2")]
+impl Sink for GzEncoder<Box<Write + Send>> {
+    fn finish(&mut self) -> io::Result<()> {
+        try!(self.try_finish());
+        self.get_mut().flush()
+    }
+
+    fn into_writer(self: Box<Self>) -> io::Result<Box<Write + Send>> {
+        let mut writer = try!(GzEncoder::finish(*self));
+        try!(writer.flush());
+        Ok(writer)
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn gzip_sink(writer: Box<Write + Send>) -> io::Result<Box<Sink>> {
+    Ok(Box::new(GzEncoder::new(writer, Compression::default())))
+}
+
+#[cfg(feature = "zstd")]
+impl Sink for ::zstd::stream::write::Encoder<'static, Box<Write + Send>> {
+    fn finish(&mut self) -> io::Result<()> {
+        try!(self.do_finish());
+        self.get_mut().flush()
+    }
+
+    fn into_writer(self: Box<Self>) -> io::Result<Box<Write + Send>> {
+        let mut writer = try!(::zstd::stream::write::Encoder::finish(*self));
+        try!(writer.flush());
+        Ok(writer)
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_sink(writer: Box<Write + Send>) -> io::Result<Box<Sink>> {
+    Ok(Box::new(try!(::zstd::stream::write::Encoder::new(writer, 0))))
+}
+
+pub struct Recorder {
+    writer: Box<Sink>,
+    new_sink: NewSink,
+    limit: Option<(usize, OnLimit)>,
+    written: usize,
+    tail: VecDeque<u8>,
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn new(writer: Box<Write + Send>, limit: Option<(usize, OnLimit)>) -> Recorder {
+        Recorder::with_sink(Box::new(Plain(writer)), plain_sink, limit)
+    }
+
+    /// Compress the recording into `writer`
+    #[cfg(any(feature = "flate2", feature = "zstd"))]
+    pub fn compressed(writer: Box<Write + Send>, codec: Codec, limit: Option<(usize, OnLimit)>) ->
+            io::Result<Recorder> {
+        let new_sink: NewSink = match codec {
+            #[cfg(feature = "flate2")]
+            Codec::Gzip => gzip_sink,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd_sink,
+        };
+        Ok(Recorder::with_sink(try!(new_sink(writer)), new_sink, limit))
+    }
+
+    fn with_sink(writer: Box<Sink>, new_sink: NewSink, limit: Option<(usize, OnLimit)>) -> Recorder {
+        Recorder {
+            writer,
+            new_sink,
+            limit,
+            written: 0,
+            tail: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) {
+        // Keep consuming the data to not block the proxy
+        if !self.failed && self.writer.write_all(buf).is_err() {
+            self.failed = true;
+        }
+    }
+
+    pub fn record(&mut self, buf: &[u8]) {
+        match self.limit {
+            None => self.write_all(buf),
+            Some((max, OnLimit::Stop)) => {
+                let len = cmp::min(buf.len(), max - self.written);
+                self.write_all(&buf[..len]);
+                self.written += len;
+            },
+            Some((max, OnLimit::Truncate)) => {
+                let buf = &buf[buf.len().saturating_sub(max)..];
+                self.tail.extend(buf.iter());
+                let excess = self.tail.len().saturating_sub(max);
+                self.tail.drain(..excess);
+            },
+        }
+    }
+
+    /// Write the `meta` header line, which is not accounted in the limit
+    ///
+    /// The `size` is used if `meta` has none.
+    pub fn header(&mut self, mut meta: SessionMeta, size: WinSize, session: SessionId) {
+        if meta.size.is_none() {
+            meta.size = Some(size);
+        }
+        meta.session = Some(session);
+        let mut line = meta.to_json();
+        line.push('\n');
+        self.write_all(line.as_bytes());
+    }
+
+    /// Record into `writer` from now on, and return the finalized previous writer
+    ///
+    /// The new writer uses the same compression and its limit starts again from zero. The
+    /// truncated output is only written to the last writer.
+    pub fn rotate(&mut self, writer: Box<Write + Send>) -> io::Result<Box<Write + Send>> {
+        let sink = try!((self.new_sink)(writer));
+        let old = mem::replace(&mut self.writer, sink);
+        self.written = 0;
+        self.failed = false;
+        old.into_writer()
+    }
+
+    /// Write the truncated output, if any, and finalize the writer
+    pub fn finish(&mut self) {
+        let tail: Vec<u8> = self.tail.drain(..).collect();
+        self.write_all(&tail);
+        if !self.failed && self.writer.finish().is_err() {
+            self.failed = true;
+        }
+    }
+}
+
+/// Record all the data read from `tap` until its end
+pub fn spawn_recorder(recorder: Arc<Mutex<Recorder>>, mut tap: File) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match tap.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if let Ok(mut recorder) = recorder.lock() {
+                        recorder.record(&buf[..n]);
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writer shared with the test once given to a recorder
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Shared {
+        fn new() -> Shared {
+            Shared(Arc::new(Mutex::new(Vec::new())))
+        }
+
+        fn data(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn limit_stop() {
+        let out = Shared::new();
+        let mut recorder = Recorder::new(Box::new(out.clone()), Some((5, OnLimit::Stop)));
+        recorder.record(b"abc");
+        recorder.record(b"def");
+        recorder.record(b"ghi");
+        recorder.finish();
+        assert_eq!(out.data(), b"abcde");
+    }
+
+    #[test]
+    fn limit_truncate() {
+        let out = Shared::new();
+        let mut recorder = Recorder::new(Box::new(out.clone()), Some((5, OnLimit::Truncate)));
+        recorder.record(b"abc");
+        recorder.record(b"defghijk");
+        recorder.record(b"lm");
+        assert!(out.data().is_empty());
+        recorder.finish();
+        assert_eq!(out.data(), b"ijklm");
+    }
+
+    fn meta() -> SessionMeta {
+        let mut meta = SessionMeta::new();
+        meta.timestamp = 42;
+        meta.user = Some("root".to_string());
+        meta
+    }
+
+    #[test]
+    fn meta_json() {
+        let mut meta = meta().env("TERM", "xterm").winsize(WinSize::from((80, 24)));
+        meta.command = vec!["sh".to_string(), "-c".to_string(), "echo \"a\\b\"\n\x01".to_string()];
+        assert_eq!(meta.to_json(),
+                   "{\"version\":1,\"timestamp\":42,\"command\":[\"sh\",\"-c\",\"echo \\\"a\\\\b\\\"\\n\\u0001\"],\
+                    \"user\":\"root\",\"env\":{\"TERM\":\"xterm\"},\"cols\":80,\"rows\":24}");
+    }
+
+    #[test]
+    fn meta_from_command() {
+        let mut cmd = Command::new("ls");
+        cmd.arg("-l").env("TERM", "vt100");
+        let meta = SessionMeta::from_command(&cmd);
+        assert_eq!(meta.command, vec!["ls".to_string(), "-l".to_string()]);
+        assert_eq!(meta.env, vec![("TERM".to_string(), "vt100".to_string())]);
+    }
+
+    #[test]
+    fn header() {
+        let out = Shared::new();
+        let mut recorder = Recorder::new(Box::new(out.clone()), Some((1, OnLimit::Stop)));
+        let mut meta = meta();
+        meta.user = None;
+        recorder.header(meta, WinSize::from((100, 30)), SessionId::next());
+        recorder.record(b"ab");
+        recorder.finish();
+        let data = String::from_utf8(out.data()).unwrap();
+        let mut lines = data.splitn(2, '\n');
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("{\"version\":1,\"timestamp\":42,\"command\":[],\"env\":{},\
+                                    \"cols\":100,\"rows\":30,\"session\":"));
+        // The header is not accounted in the limit
+        assert_eq!(lines.next(), Some("a"));
+    }
+
+
+    #[test]
+    fn rotate() {
+        let (first, second) = (Shared::new(), Shared::new());
+        let mut recorder = Recorder::new(Box::new(first.clone()), Some((4, OnLimit::Stop)));
+        recorder.record(b"abc");
+        recorder.rotate(Box::new(second.clone())).unwrap();
+        // The limit starts again from zero
+        recorder.record(b"defgh");
+        recorder.finish();
+        assert_eq!(first.data(), b"abc");
+        assert_eq!(second.data(), b"defg");
+    }
+
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzip() {
+        let out = Shared::new();
+        let mut recorder = Recorder::compressed(Box::new(out.clone()), Codec::Gzip, None).unwrap();
+        recorder.record(b"hello ");
+        recorder.record(b"world");
+        recorder.finish();
+        let mut data = String::new();
+        ::flate2::read::GzDecoder::new(&out.data()[..]).read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello world");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        let out = Shared::new();
+        let mut recorder = Recorder::compressed(Box::new(out.clone()), Codec::Zstd, None).unwrap();
+        recorder.record(b"hello world");
+        recorder.finish();
+        assert_eq!(::zstd::stream::decode_all(&out.data()[..]).unwrap(), b"hello world");
+    }
+
+}
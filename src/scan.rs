@@ -0,0 +1,80 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+
+// Position of the first `pattern` in `buf`
+pub fn find(buf: &[u8], pattern: &[u8]) -> Option<usize> {
+    buf.windows(pattern.len()).position(|w| w == pattern)
+}
+
+// Length of the longest end of `buf` which is a beginning of `pattern`, i.e. what to hold back
+// when `pattern` may be split across two reads
+pub fn partial_len(buf: &[u8], pattern: &[u8]) -> usize {
+    let max = cmp::min(pattern.len() - 1, buf.len());
+    (1..max + 1).rev().find(|&n| buf.ends_with(&pattern[..n])).unwrap_or(0)
+}
+
+// Length of an incomplete UTF-8 sequence at the end of `buf`, i.e. what to hold back when a
+// character may be split across two reads (at most 3 bytes, invalid sequences are not held)
+pub fn utf8_partial_len(buf: &[u8]) -> usize {
+    for len in 1..cmp::min(buf.len(), 3) + 1 {
+        let c = buf[buf.len() - len];
+        if c & 0xc0 != 0x80 {
+            // Length of the sequence started by this leading byte
+            let full = match c {
+                0xc2..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf4 => 4,
+                _ => 0,
+            };
+            return if full > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_pattern() {
+        assert_eq!(find(b"ab\x1b[200~", b"\x1b[200~"), Some(2));
+        assert_eq!(find(b"ab\x1b[200", b"\x1b[200~"), None);
+        assert_eq!(find(b"", b"\x1b"), None);
+    }
+
+    #[test]
+    fn partial_pattern() {
+        assert_eq!(partial_len(b"ab\x1b[20", b"\x1b[200~"), 4);
+        assert_eq!(partial_len(b"ab\x1b", b"\x1b[200~"), 1);
+        assert_eq!(partial_len(b"ab", b"\x1b[200~"), 0);
+        // A complete pattern is not held back
+        assert_eq!(partial_len(b"\x1b[200~", b"\x1b[200~"), 0);
+    }
+
+    #[test]
+    fn partial_utf8() {
+        let euro = "\u{20ac}".as_bytes();
+        assert_eq!(utf8_partial_len(&euro[..1]), 1);
+        assert_eq!(utf8_partial_len(&euro[..2]), 2);
+        assert_eq!(utf8_partial_len(euro), 0);
+        assert_eq!(utf8_partial_len(b"a\xf0\x9f\x98"), 3);
+        assert_eq!(utf8_partial_len(b"ab"), 0);
+        // Invalid sequences are not held back
+        assert_eq!(utf8_partial_len(b"a\x80\x80\x80"), 0);
+        assert_eq!(utf8_partial_len(b"a\xff"), 0);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use fd::set_flags;
+use get_flags;
+use libc;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+/// Slave side of a TTY used without any process (e.g. to emulate one)
+///
+/// The data written to the slave is read from the master and conversely. If a process is
+/// connected to the same slave, the reads consume its input, which is rarely intended.
+pub struct SlaveHandle {
+    file: File,
+}
+
+impl SlaveHandle {
+    pub fn new(file: File) -> SlaveHandle {
+        SlaveHandle {
+            file,
+        }
+    }
+
+    /// Read some data written to the master, without requiring a mutable reference
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    /// Write some data to be read from the master, without requiring a mutable reference
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        (&self.file).write(buf)
+    }
+
+    /// Same as `write()` but write all the data
+    pub fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        (&self.file).write_all(buf)
+    }
+
+    /// Make the reads and writes return a `WouldBlock` error instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let flags = try!(get_flags(fd));
+        set_flags(fd, if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        })
+    }
+
+    /// Get back the slave file
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+impl Read for SlaveHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SlaveHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for SlaveHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SlaveHandle {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TtyServer;
+
+    #[test]
+    fn read_write() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let slave = server.take_slave_handle().unwrap();
+        assert!(server.take_slave_handle().is_none());
+        let mut master = server.get_master();
+        let mut buf = [0; 2];
+        slave.write_all(b"hi").unwrap();
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        master.write_all(b"ho").unwrap();
+        assert_eq!(slave.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf, b"ho");
+        slave.set_nonblocking(true).unwrap();
+        assert_eq!(slave.read(&mut buf).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+/// Write to a TTY master from multiple threads without interleaving the data
+///
+/// Each write is done as a whole with respect to the other `MasterWriter`s of the same
+/// `TtyServer`, but not to a `TtyClient` forwarding its peer input.
+#[derive(Clone)]
+pub struct MasterWriter {
+    master: Arc<File>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl MasterWriter {
+    pub fn new(master: File, lock: Arc<Mutex<()>>) -> MasterWriter {
+        MasterWriter {
+            master: Arc::new(master),
+            lock,
+        }
+    }
+
+    /// Write all of `buf` to the master (i.e. as the slave input)
+    ///
+    /// A `BrokenPipe` error is returned once all the slave file descriptors are closed (e.g. the
+    /// process exited) instead of losing the data.
+    pub fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        // A panic while writing doesn't invalidate the lock
+        let _guard = match self.lock.lock() {
+            Ok(g) => g,
+            Err(e) => e.into_inner(),
+        };
+        if self.is_hangup() {
+            return Err(broken_pipe());
+        }
+        match (&*self.master).write_all(buf) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Err(broken_pipe()),
+            r => r,
+        }
+    }
+
+    fn is_hangup(&self) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pfd, 1, 0) == 1 && pfd.revents & libc::POLLHUP != 0 }
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "No more TTY slave")
+}
+
+impl Write for MasterWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(MasterWriter::write_all(self, buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::process::Command;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use TtyServer;
+
+    #[test]
+    fn serialized() {
+        let mut server = TtyServer::new_raw(None).unwrap();
+        let mut cmd = Command::new("head");
+        cmd.arg("-c").arg("2");
+        let mut child = server.spawn(cmd).unwrap();
+        let writer = server.master_writer().unwrap();
+        let (tx, rx) = channel();
+        {
+            // Another writer of the same server is busy
+            let _guard = server.write_lock.lock().unwrap();
+            thread::spawn(move || {
+                writer.write_all(b"hi").unwrap();
+                tx.send(()).unwrap();
+            });
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        }
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(child.wait().unwrap().success());
+        let mut out = [0; 2];
+        server.get_master().read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"hi");
+    }
+
+    #[test]
+    fn broken_pipe() {
+        let mut server = TtyServer::new_default().unwrap();
+        let mut writer = server.master_writer().unwrap();
+        writer.write_all(b"hi").unwrap();
+        drop(server.take_slave());
+        let err = MasterWriter::write_all(&writer, b"lost").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(writer.write(b"lost").err().unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
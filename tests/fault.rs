@@ -0,0 +1,82 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// The faults are global to the process, hence this single test lives in its own binary instead of
+// with the unit tests running concurrently.
+
+#![cfg(feature = "fault-injection")]
+
+extern crate libc;
+extern crate termios;
+extern crate tty;
+
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use termios::Termios;
+use tty::{AppendFlagPolicy, PeerSetupError, Syscall, TtyClientBuilder, TtyServer, clear_fault,
+          inject_fault};
+use tty::ffi::{grantpt, openpty};
+
+#[test]
+fn faults() {
+    inject_fault(Syscall::Grantpt, libc::EACCES);
+    let mut pty = openpty(None, None).unwrap();
+    assert_eq!(grantpt(&mut pty.master).err().unwrap().raw_os_error(), Some(libc::EACCES));
+    // Only the slave opening is relevant
+    TtyServer::new_default().unwrap();
+    clear_fault(Syscall::Grantpt);
+    grantpt(&mut pty.master).unwrap();
+
+    inject_fault(Syscall::Tcsetattr, libc::EIO);
+    let err = TtyServer::new_raw(None).err().unwrap();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    clear_fault(Syscall::Tcsetattr);
+    TtyServer::new_raw(None).unwrap();
+
+    let server = TtyServer::new_default().unwrap();
+    let mut peer = TtyServer::new_default().unwrap();
+    let peer_termios = Termios::from_fd(peer.get_master().as_raw_fd()).unwrap();
+    inject_fault(Syscall::Tcsetattr, libc::EIO);
+    let err = server.new_client(peer.take_slave().unwrap(), None).err().unwrap();
+    clear_fault(Syscall::Tcsetattr);
+    assert!(err.to_string().starts_with("Failed to configure the peer TTY"));
+    let cause = err.get_ref().unwrap().downcast_ref::<PeerSetupError>().unwrap();
+    assert_eq!(cause.raw_os_error(), Some(libc::EIO));
+    assert_eq!(Termios::from_fd(peer.get_master().as_raw_fd()).unwrap(), peer_termios);
+
+    // The append flag can't be unset on either side
+    let mut peer = TtyServer::new_default().unwrap();
+    inject_fault(Syscall::UnsetAppendFlag, libc::EIO);
+    let builder = TtyClientBuilder::new().append_flag_policy(AppendFlagPolicy::Lenient);
+    let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+    drop(client);
+    let mut peer = TtyServer::new_default().unwrap();
+    let builder = TtyClientBuilder::new().append_flag_policy(AppendFlagPolicy::Strict);
+    let err = server.new_client_with(builder, peer.take_slave().unwrap(), None).err().unwrap();
+    clear_fault(Syscall::UnsetAppendFlag);
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+    let mut server = TtyServer::new_default().unwrap();
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("echo hi && sleep 1");
+    let mut child = server.spawn(cmd).unwrap();
+    let mut peer = TtyServer::new_default().unwrap();
+    inject_fault(Syscall::Splice, libc::EINVAL);
+    let client = server.new_client(peer.take_slave().unwrap(), None).unwrap();
+    // The proxy stops on the first forward
+    client.wait();
+    clear_fault(Syscall::Splice);
+    assert_eq!(client.take_error().unwrap().raw_os_error(), Some(libc::EINVAL));
+    assert!(child.wait().unwrap().success());
+}
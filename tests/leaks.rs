@@ -0,0 +1,55 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// The threads and the file descriptors are counted for the whole process, hence this single test
+// lives in its own binary instead of with the unit tests running concurrently.
+
+extern crate tty;
+
+use std::fs::read_dir;
+use std::io::Write;
+use tty::{BufferPool, TtyClientBuilder, TtyServer};
+
+fn count_entries(dir: &str) -> usize {
+    read_dir(dir).unwrap().count()
+}
+
+// Bind a client and drop it while the output is still forwarded
+fn create_drop(builder: TtyClientBuilder) {
+    let mut server = TtyServer::new_default().unwrap();
+    let mut slave = server.take_slave().unwrap();
+    let mut peer = TtyServer::new_default().unwrap();
+    let client = server.new_client_with(builder, peer.take_slave().unwrap(), None).unwrap();
+    slave.write_all(b"hello\n").unwrap();
+    drop(client);
+}
+
+#[test]
+fn create_drop_clients() {
+    // Both the splice(2) and the read(2) proxies
+    let builders = || vec![TtyClientBuilder::new(), TtyClientBuilder::new().buffer_pool(BufferPool::new(2, 16))];
+    // Some resources are only allocated once
+    for builder in builders() {
+        create_drop(builder);
+    }
+    let threads = count_entries("/proc/self/task");
+    let fds = count_entries("/proc/self/fd");
+    for _ in 0..200 {
+        for builder in builders() {
+            create_drop(builder);
+        }
+    }
+    assert_eq!(count_entries("/proc/self/task"), threads);
+    assert_eq!(count_entries("/proc/self/fd"), fds);
+}